@@ -1,25 +1,98 @@
 #![allow(dead_code)]
 #![allow(warnings, unused)]
 
+use clap::{CommandFactory, Parser};
 use std::process::exit;
 pub mod rulox;
-use rulox::CliRulox;
+use rulox::{read_script, AstFormat, CliRulox, Inspect};
+
+/// Runs Lox scripts, or starts a REPL when given none
+#[derive(Parser)]
+#[command(name = "rulox", bin_name = "rulox")]
+struct Args {
+    /// Script to run, or `-` to read it from stdin. Several can be given with --check
+    scripts: Vec<String>,
+
+    /// Run the given code instead of a script
+    #[arg(short, long, value_name = "CODE", conflicts_with = "scripts")]
+    eval: Option<String>,
+
+    /// Print the tokens scanned from the source instead of running it
+    #[arg(long)]
+    tokens: bool,
+
+    /// Print the syntax tree instead of running the source
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "sexpr"
+    )]
+    ast: Option<AstFormat>,
+
+    /// Print the scope each variable reference resolves to instead of running the source
+    #[arg(long)]
+    resolve: bool,
+
+    /// Only check the source for errors, without running it
+    #[arg(long)]
+    check: bool,
+}
 
 fn main() {
+    let args = Args::parse();
     let mut clirulox = CliRulox::new();
 
-    let args: Vec<String> = std::env::args().collect();
+    let inspecting = args.tokens || args.ast.is_some() || args.resolve || args.check;
+    let options = Inspect {
+        tokens: args.tokens,
+        ast: args.ast,
+        resolve: args.resolve,
+    };
 
-    match args.len() {
-        len if len > 2 => {
-            println!("Usage: rulox [script]");
-            exit(64);
+    if let Some(code) = args.eval {
+        if inspecting {
+            clirulox.inspect("<eval>", code, &options);
+        } else {
+            clirulox.run_source(code);
+        }
+    } else if args.scripts.is_empty() {
+        if inspecting {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "nothing to inspect, pass a script, `-` or --eval",
+                )
+                .exit();
+        }
+        clirulox.run_prompt();
+    } else if inspecting {
+        if args.scripts.len() > 1 && !args.check {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::TooManyValues,
+                    "only --check accepts more than one script",
+                )
+                .exit();
         }
-        len if len == 2 => {
-            clirulox.run_file(&args[1]);
+        for script in args.scripts.iter() {
+            clirulox.inspect(script, read_script(script), &options);
         }
-        _ => {
-            clirulox.run_prompt();
+    } else {
+        match args.scripts.as_slice() {
+            [script] if script == "-" => clirulox.run_source(read_script(script)),
+            [script] => clirulox.run_file(script),
+            _ => Args::command()
+                .error(
+                    clap::error::ErrorKind::TooManyValues,
+                    "only one script can be run at a time",
+                )
+                .exit(),
         }
     }
+
+    if clirulox.had_errors() {
+        exit(65);
+    }
 }
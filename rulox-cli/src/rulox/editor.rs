@@ -0,0 +1,77 @@
+use rulox_core::rulox::scanner::KEYWORDS;
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use std::path::PathBuf;
+
+use super::COMMANDS;
+
+// Plugs into the line editor to tab-complete keywords, meta-commands and the globals
+// defined so far
+#[derive(Default)]
+pub struct LoxHelper {
+    // Refreshed from the session after every input it runs
+    pub globals: Vec<String>,
+}
+
+impl Completer for LoxHelper {
+    type Candidate = String;
+
+    // Completes the identifier that ends at the cursor, or the command at the start of the line
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let typed = &line[..pos];
+        if typed.starts_with(':') && !typed.contains(char::is_whitespace) {
+            let commands = COMMANDS
+                .iter()
+                .map(|(command, _, _)| command.to_string())
+                .filter(|command| command.starts_with(typed))
+                .collect();
+            return Ok((0, commands));
+        }
+
+        let start = line[..pos]
+            .char_indices()
+            .rev()
+            .find(|&(_, c)| !(c.is_alphanumeric() || c == '_'))
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let word = &line[start..pos];
+        if word.is_empty() {
+            return Ok((pos, Vec::new()));
+        }
+
+        let mut candidates: Vec<String> = KEYWORDS
+            .iter()
+            .map(|keyword| keyword.to_string())
+            .chain(self.globals.iter().cloned())
+            .filter(|candidate| candidate.starts_with(word))
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for LoxHelper {
+    type Hint = String;
+}
+
+impl Highlighter for LoxHelper {}
+
+// Incomplete input is handled by the REPL loop itself, which shows a continuation prompt
+impl Validator for LoxHelper {}
+
+impl Helper for LoxHelper {}
+
+// History is kept in the home directory, so it's shared by every REPL session
+pub fn history_path() -> Option<PathBuf> {
+    std::env::home_dir().map(|home| home.join(".rulox_history"))
+}
@@ -1,11 +1,55 @@
 use rulox_core::rulox::{
-    ast::Stmt, interpreter::Interpreter, parser::Parser, resolver::Resolver, scanner::Scanner,
-    token::Token, Rulox,
+    ast::Stmt, diagnostic::Diagnostic, interpreter::Interpreter, modules::FileLoader,
+    output::Writer, parser::Parser, resolver::Resolver, scanner::Scanner, token::Token, Rulox,
 };
 
+use clap::ValueEnum;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::Editor;
+
 use std::fs;
 use std::io::{self, Read, Write};
+use std::path::Path;
 use std::process::exit;
+use std::time::Instant;
+
+mod editor;
+use editor::{history_path, LoxHelper};
+
+// REPL meta-commands, with the argument each one expects and what it does
+const COMMANDS: [(&str, &str, &str); 7] = [
+    (":tokens", "<code>", "Show the tokens scanned from the code"),
+    (
+        ":ast",
+        "<code>",
+        "Show the syntax tree parsed from the code",
+    ),
+    (":env", "", "Show the variables defined in the session"),
+    (":load", "<file>", "Run a file in the session"),
+    (":reset", "", "Start over with an empty session"),
+    (
+        ":time",
+        "<expr>",
+        "Evaluate the expression in the session and show its value and how long it took",
+    ),
+    (":help", "", "Show this list"),
+];
+
+// How `--ast` prints the syntax tree
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum AstFormat {
+    Sexpr,
+    Json,
+}
+
+// The stages of the pipeline to print when inspecting a script rather than running it
+#[derive(Debug, Default)]
+pub struct Inspect {
+    pub tokens: bool,
+    pub ast: Option<AstFormat>,
+    pub resolve: bool,
+}
 
 pub struct CliRulox {
     had_errors: bool,
@@ -17,68 +61,263 @@ impl CliRulox {
     }
 
     pub fn run_file(&mut self, path: &str) {
-        let source = fs::read_to_string(path).expect("Unable to read file.");
+        let source = read_script(path);
 
         println!("Opening file {}...", path);
 
-        self.run(source);
+        // A script imports modules relative to its own directory
+        let root = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        self.run_source_in(source, root);
+    }
+
+    pub fn run_source(&mut self, source: String) {
+        self.run_source_in(source, Path::new(""));
+    }
+
+    fn run_source_in(&mut self, source: String, root: &Path) {
+        let mut rulox = Rulox::new(source);
+        rulox.set_loader(FileLoader::new(root));
+        // Long running scripts show their output as they go
+        rulox.set_output(Writer(io::stdout()));
+        rulox.tokenize();
+        self.run(&mut rulox);
 
         if self.had_errors {
             exit(65);
         };
     }
 
+    // Takes the source as far as resolving without running it, printing the requested stages
+    // along the way. Diagnostics are prefixed with `name`, since many files can be checked
+    // in one go; errors in any of them are remembered in `had_errors`.
+    pub fn inspect(&mut self, name: &str, source: String, options: &Inspect) {
+        let mut rulox = Rulox::new(source);
+
+        rulox.tokenize();
+        if options.tokens {
+            rulox.tokens().iter().for_each(|token| {
+                println!("{}", token);
+            });
+        }
+
+        rulox.parse();
+        match options.ast {
+            Some(AstFormat::Sexpr) => rulox.parse_tree().iter().for_each(|stmt| {
+                println!("{}", stmt);
+            }),
+            Some(AstFormat::Json) => {
+                let json = serde_json::to_string_pretty(rulox.parse_tree())
+                    .expect("Unable to serialize the syntax tree.");
+                println!("{}", json);
+            }
+            None => {}
+        }
+
+        rulox.resolve();
+        if options.resolve {
+            rulox.resolutions().iter().for_each(|resolution| {
+                println!("{}", resolution);
+            });
+        }
+
+        rulox.diagnostics().iter().for_each(|diagnostic| {
+            println!("{}: {}", name, diagnostic);
+        });
+        self.had_errors |= rulox.had_errors;
+    }
+
+    pub fn had_errors(&self) -> bool {
+        self.had_errors
+    }
+
     pub fn run_prompt(&mut self) {
+        let mut editor: Editor<LoxHelper, DefaultHistory> =
+            Editor::new().expect("Unable to open the line editor.");
+        editor.set_helper(Some(LoxHelper::default()));
+
+        let history = history_path();
+        if let Some(path) = &history {
+            // There's no history to load on the very first run
+            let _ = editor.load_history(path);
+        }
+
+        // Lines are gathered here until they form a complete input
         let mut input = String::new();
+        // Shared by every input, so definitions stay around for the ones after
+        let mut session = new_session();
 
         println!(" -----------------------------------------------");
         println!(" ---- Welcome to the Rulox Interpreter REPL ----");
         println!(" ----    (use 'exit' or 'quit' to close)     ----");
+        println!(" ----     (or ':help' for more commands)     ----");
         println!(" -----------------------------------------------");
         loop {
-            print!("rulox # ");
+            let prompt = if input.is_empty() {
+                "rulox # "
+            } else {
+                "   ... "
+            };
+
+            match editor.readline(prompt) {
+                Ok(line) => {
+                    if input.is_empty() && matches!(line.trim(), "exit" | "quit") {
+                        break;
+                    }
+
+                    if input.is_empty() && line.trim_start().starts_with(':') {
+                        let _ = editor.add_history_entry(line.trim());
+                        self.command(&mut session, line.trim());
+                    } else {
+                        input.push_str(&line);
+                        input.push('\n');
 
-            // Need to flush stdout before reading a new line, otherwise `>` is prepended to input
-            io::stdout().flush().unwrap();
+                        // Keep reading while a brace, string or comment is left open
+                        session.load(input.clone());
+                        session.tokenize();
+                        if session.is_incomplete() {
+                            continue;
+                        }
 
-            match io::stdin().read_line(&mut input) {
-                Ok(_) => match input.as_str() {
-                    "exit\n" | "quit\n" => {
-                        exit(0);
+                        if !input.trim().is_empty() {
+                            let _ = editor.add_history_entry(input.trim_end());
+                        }
+                        self.run(&mut session);
                     }
-                    _ => self.run(input.clone()),
-                },
-                Err(error) => println!("error: {}", error),
-            }
 
-            // Since read_line appends to buffer, need to clear
-            input.clear();
+                    if let Some(helper) = editor.helper_mut() {
+                        helper.globals = session
+                            .global_names()
+                            .iter()
+                            .map(|name| name.to_string())
+                            .collect();
+                    }
+                    input.clear();
 
-            // Reset error flag in interactive loop
-            self.had_errors = false;
+                    // Reset error flag in interactive loop
+                    self.had_errors = false;
+                }
+                // Ctrl-C throws away the input typed so far, Ctrl-D closes the REPL
+                Err(ReadlineError::Interrupted) => input.clear(),
+                Err(ReadlineError::Eof) => break,
+                Err(error) => {
+                    println!("error: {}", error);
+                    break;
+                }
+            }
+        }
+
+        if let Some(path) = &history {
+            if let Err(error) = editor.save_history(path) {
+                println!("Unable to save history: {}", error);
+            }
         }
     }
 
-    fn run(&mut self, source: String) {
-        let mut rulox = Rulox::new(source);
+    // Runs a meta-command line such as `:env` or `:ast var x = 1;`
+    fn command(&mut self, session: &mut Rulox, line: &str) {
+        let (name, argument) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let argument = argument.trim();
 
-        if rulox.had_errors {
-            self.report_errors(rulox.error_msg);
-            exit(65);
-        };
+        match COMMANDS.iter().find(|(command, _, _)| *command == name) {
+            None => {
+                println!("Unknown command {}, see :help for the list", name);
+                return;
+            }
+            Some((_, expected, _)) if !expected.is_empty() && argument.is_empty() => {
+                println!("Usage: {} {}", name, expected);
+                return;
+            }
+            _ => {}
+        }
 
-        rulox.tokenize();
+        match name {
+            ":tokens" => {
+                let mut rulox = Rulox::new(argument.to_string());
+                rulox.tokenize();
+                rulox.tokens().iter().for_each(|token| {
+                    println!("{}", token);
+                });
+                self.report_diagnostics(rulox.diagnostics());
+            }
+            ":ast" => {
+                let mut rulox = Rulox::new(argument.to_string());
+                rulox.tokenize();
+                rulox.parse();
+                rulox.parse_tree().iter().for_each(|stmt| {
+                    println!("{}", stmt);
+                });
+                self.report_diagnostics(rulox.diagnostics());
+            }
+            ":env" => println!("{}", session.get_environment()),
+            ":load" => match fs::read_to_string(argument) {
+                Ok(source) => {
+                    session.load(source);
+                    session.tokenize();
+                    self.run(session);
+                }
+                Err(error) => println!("Unable to read {}: {}", argument, error),
+            },
+            ":reset" => *session = new_session(),
+            ":time" => {
+                // `:time f(3)` is an expression, which needs a `;` to parse as a statement.
+                // Statements can be timed too, as long as they're complete.
+                let mut source = argument.trim_end().to_string();
+                if !source.ends_with(';') && !source.ends_with('}') {
+                    source.push(';');
+                }
+                let start = Instant::now();
+                session.load(source);
+                session.tokenize();
+                self.run(session);
+                println!("Took {:.3?}", start.elapsed());
+            }
+            _ => {
+                for (command, argument, description) in COMMANDS.iter() {
+                    println!(
+                        "  {:<16} {}",
+                        format!("{} {}", command, argument),
+                        description
+                    );
+                }
+            }
+        }
+    }
+
+    // Parses and runs input that has already been tokenized
+    fn run(&mut self, rulox: &mut Rulox) {
         rulox.parse();
-        let output = rulox.run();
+        rulox.resolve();
+        // The program's output goes straight to stdout while it runs
+        rulox.run();
 
-        output.iter().for_each(|line| {
-            println!("{}", line);
-        });
+        self.report_diagnostics(rulox.diagnostics());
+        self.had_errors = rulox.had_errors;
     }
 
-    fn report_errors(&self, errors: Vec<String>) {
-        errors.iter().for_each(|err| {
-            println!("{}", err);
+    fn report_diagnostics(&self, diagnostics: &[Diagnostic]) {
+        diagnostics.iter().for_each(|diagnostic| {
+            println!("{}", diagnostic);
         });
     }
 }
+
+fn new_session() -> Rulox {
+    let mut session = Rulox::interactive();
+    session.set_output(Writer(io::stdout()));
+    session
+}
+
+// Reads a script from its path, or from stdin for `-`
+pub fn read_script(path: &str) -> String {
+    let mut source = String::new();
+    let result = if path == "-" {
+        io::stdin().read_to_string(&mut source).map(|_| source)
+    } else {
+        fs::read_to_string(path)
+    };
+
+    result.unwrap_or_else(|error| {
+        println!("Unable to read {}: {}", path, error);
+        exit(66);
+    })
+}
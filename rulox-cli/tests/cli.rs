@@ -0,0 +1,86 @@
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+// Writes `source` to a script named `name` in a scratch directory and runs the CLI on it
+fn rulox(args: &[&str], name: &str, source: &str) -> Output {
+    let dir = std::env::temp_dir().join(format!("rulox-cli-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    fs::write(&path, source).unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_rulox-cli"))
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn check_fails_on_resolver_errors() {
+    let output = rulox(
+        &["--check"],
+        "broken.lox",
+        "return 1;\nbreak;\nprint this;\n",
+    );
+    assert_eq!(output.status.code(), Some(65));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.matches("Resolve error").count(), 3, "{}", stdout);
+}
+
+#[test]
+fn check_passes_on_a_valid_file() {
+    let output = rulox(
+        &["--check"],
+        "valid.lox",
+        "fun f() { return 1; }\nprint f();\n",
+    );
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn running_a_file_with_resolver_errors_fails() {
+    let output = rulox(&[], "return.lox", "print 1;\nreturn 1;\n");
+    assert_eq!(output.status.code(), Some(65));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.lines().any(|line| line == "1"), "{}", stdout);
+}
+
+// The limit on nested calls has to be reached before the native stack runs out, even in
+// the debug build the tests run
+#[test]
+fn deep_recursion_is_a_stack_overflow_error() {
+    let source = "class A {\n  m(n) {\n    if (n == 0) return 0;\n    try { return 1 + len([this.m(n - 1)]); } finally {}\n  }\n}\nprint A().m(2000);\n";
+    let output = rulox(&[], "deep.lox", source);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Stack overflow"), "{}", stdout);
+    assert!(stdout.contains("in m() called at 4:"), "{}", stdout);
+    assert_eq!(output.status.code(), Some(65));
+}
+
+#[test]
+fn time_evaluates_an_expression() {
+    // The REPL keeps its history in the home directory
+    let mut repl = Command::new(env!("CARGO_BIN_EXE_rulox-cli"))
+        .env("HOME", std::env::temp_dir())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let input = "fun f(n) { return n * 2; }\n:time f(3)\n";
+    repl.stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = repl.wait_with_output().unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines().skip_while(|line| *line != "6");
+    assert_eq!(lines.next(), Some("6"), "{}", stdout);
+    assert!(
+        lines.next().unwrap_or("").starts_with("Took "),
+        "{}",
+        stdout
+    );
+}
@@ -1,12 +1,48 @@
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 
-use super::function::LoxFunction;
-use super::token::TokenType;
+use super::diagnostic::Span;
+use super::function::{LoxCallable, LoxClass, LoxInstance};
+use super::interpreter::RuntimeError;
+
+// Every expression that refers to a variable carries a unique id, which the resolver
+// uses to record how many scopes away that particular reference is bound.
+// Ids come from a global counter so they stay unique across separate parses.
+pub type ExprId = usize;
+
+static NEXT_EXPR_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // The lists and maps being displayed right now, so that one containing itself is shown
+    // as `[...]` or `{...}` the second time round instead of being displayed forever
+    static DISPLAYING: RefCell<Vec<*const ()>> = const { RefCell::new(Vec::new()) };
+}
+
+pub fn next_expr_id() -> ExprId {
+    NEXT_EXPR_ID.fetch_add(1, AtomicOrdering::Relaxed)
+}
+
+// Every node of the syntax tree records the range of source it was parsed from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Expr {
+pub enum ExprKind {
     Binary {
         lh_expr: Box<Expr>,
         op: BiOperator,
@@ -18,10 +54,14 @@ pub enum Expr {
     },
     Literal(Value),
     Assign {
+        id: ExprId,
         name: Rc<str>,
         value: Box<Expr>,
     },
-    Variable(Rc<str>),
+    Variable {
+        id: ExprId,
+        name: Rc<str>,
+    },
     Logical {
         lh_expr: Box<Expr>,
         op: LogicOperator,
@@ -29,18 +69,62 @@ pub enum Expr {
     },
     Call {
         callee: Box<Expr>,
-        paren: TokenType,
         arguments: Vec<Expr>,
     },
+    Get {
+        object: Box<Expr>,
+        name: Rc<str>,
+    },
+    Set {
+        object: Box<Expr>,
+        name: Rc<str>,
+        value: Box<Expr>,
+    },
+    This {
+        id: ExprId,
+    },
+    List(Vec<Expr>),
+    Map(Vec<(Expr, Expr)>),
+    Index {
+        object: Box<Expr>,
+        index: Box<Expr>,
+    },
+    SetIndex {
+        object: Box<Expr>,
+        index: Box<Expr>,
+        value: Box<Expr>,
+    },
+    Super {
+        id: ExprId,
+        method: Rc<str>,
+    },
+    // An anonymous function, e.g. `fun (a, b) { return a + b; }`
+    // The body is shared with every function value created from it
+    Lambda {
+        parameters: Vec<Rc<str>>,
+        body: Rc<[Stmt]>,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Stmt {
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StmtKind {
     Expression(Expr),
     Function {
         name: Rc<str>,
         parameters: Vec<Rc<str>>,
-        body: Vec<Stmt>,
+        body: Rc<[Stmt]>,
     },
     Print(Expr),
     Return(Expr),
@@ -57,10 +141,26 @@ pub enum Stmt {
     While {
         condition: Expr,
         body: Box<Stmt>,
+        increment: Option<Expr>, // Only set by desugared for loops
     },
+    Break,
+    Continue,
     Class {
         name: Rc<str>,
-        methods: Vec<Stmt>, // Assume that they're all Stmt::Function
+        superclass: Option<Expr>, // Assume that it's an ExprKind::Variable
+        methods: Vec<Stmt>,       // Assume that they're all StmtKind::Function
+    },
+    Throw(Expr),
+    // Has a catch clause, a finally clause or both
+    Try {
+        body: Vec<Stmt>,
+        catch: Option<(Rc<str>, Vec<Stmt>)>, // The name the error is bound to, and the handler
+        finally: Option<Vec<Stmt>>,
+    },
+    // Binds the namespace of the module found at `path` to `name`
+    Import {
+        name: Rc<str>,
+        path: String,
     },
 }
 
@@ -70,11 +170,17 @@ pub enum Value {
     Number(f64),
     Boolean(bool),
     Nil,
-    Callable(Rc<LoxFunction>),
+    // Functions (including natives) only exist at runtime, never in a serialized tree
+    #[serde(skip)]
+    Callable(Rc<dyn LoxCallable>),
+    Class(Rc<LoxClass>),
+    Instance(Rc<LoxInstance>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<HashMap<MapKey, Value>>>),
 }
 
 impl Value {
-    pub fn into_callable(self) -> Option<Rc<LoxFunction>> {
+    pub fn into_callable(self) -> Option<Rc<dyn LoxCallable>> {
         match self {
             Value::Callable(function) => Some(function),
             _ => None,
@@ -85,16 +191,102 @@ impl Value {
 impl PartialEq for Value {
     fn eq(&self, other: &Value) -> bool {
         match (self, other) {
-            (&Value::String(ref left), &Value::String(ref right)) => left == right,
-            (&Value::Number(left), &Value::Number(right)) => left == right,
-            (&Value::Boolean(left), &Value::Boolean(right)) => left == right,
-            (&Value::Nil, &Value::Nil) => true,
-            (&Value::Callable(ref left), &Value::Callable(ref right)) => Rc::ptr_eq(left, right),
+            (Value::String(left), Value::String(right)) => left == right,
+            (Value::Number(left), Value::Number(right)) => left == right,
+            (Value::Boolean(left), Value::Boolean(right)) => left == right,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Callable(left), Value::Callable(right)) => Rc::ptr_eq(left, right),
+            (Value::Class(left), Value::Class(right)) => Rc::ptr_eq(left, right),
+            (Value::Instance(left), Value::Instance(right)) => Rc::ptr_eq(left, right),
+            (Value::List(left), Value::List(right)) => Rc::ptr_eq(left, right),
+            (Value::Map(left), Value::Map(right)) => Rc::ptr_eq(left, right),
             _ => false,
         }
     }
 }
 
+// Only the primitive values can be used as map keys. Numbers are compared and hashed
+// by their bits, with -0 normalized to 0, so that keys that are == also hash the same.
+// Keys are ordered nil < booleans < numbers < strings, which gives maps a stable
+// order when printing them or listing their keys.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MapKey {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    String(Box<String>),
+}
+
+impl MapKey {
+    pub fn from_value(val: &Value) -> Result<MapKey, RuntimeError> {
+        match val {
+            Value::Nil => Ok(MapKey::Nil),
+            Value::Boolean(b) => Ok(MapKey::Boolean(*b)),
+            Value::Number(num) if *num == 0.0 => Ok(MapKey::Number(0.0)),
+            Value::Number(num) => Ok(MapKey::Number(*num)),
+            Value::String(s) => Ok(MapKey::String(s.clone())),
+            _ => Err(RuntimeError::type_error(
+                val,
+                "Map keys must be strings, numbers, booleans or nil.",
+            )),
+        }
+    }
+
+    pub fn into_value(self) -> Value {
+        match self {
+            MapKey::Nil => Value::Nil,
+            MapKey::Boolean(b) => Value::Boolean(b),
+            MapKey::Number(num) => Value::Number(num),
+            MapKey::String(s) => Value::String(s),
+        }
+    }
+}
+
+impl PartialEq for MapKey {
+    fn eq(&self, other: &MapKey) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MapKey {}
+
+impl Hash for MapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            MapKey::Nil => 0.hash(state),
+            MapKey::Boolean(b) => b.hash(state),
+            MapKey::Number(num) => num.to_bits().hash(state),
+            MapKey::String(s) => s.hash(state),
+        }
+    }
+}
+
+impl PartialOrd for MapKey {
+    fn partial_cmp(&self, other: &MapKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MapKey {
+    fn cmp(&self, other: &MapKey) -> Ordering {
+        fn rank(key: &MapKey) -> u8 {
+            match key {
+                MapKey::Nil => 0,
+                MapKey::Boolean(_) => 1,
+                MapKey::Number(_) => 2,
+                MapKey::String(_) => 3,
+            }
+        }
+
+        match (self, other) {
+            (MapKey::Boolean(left), MapKey::Boolean(right)) => left.cmp(right),
+            (MapKey::Number(left), MapKey::Number(right)) => left.total_cmp(right),
+            (MapKey::String(left), MapKey::String(right)) => left.cmp(right),
+            _ => rank(self).cmp(&rank(other)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BiOperator {
     Plus,
@@ -113,6 +305,8 @@ pub enum BiOperator {
 pub enum UnOperator {
     Minus,
     Bang,
+    // Only produced by string interpolation, there's no syntax for it
+    Stringify,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -143,6 +337,7 @@ impl fmt::Display for UnOperator {
         match self {
             UnOperator::Bang => write!(f, "!"),
             UnOperator::Minus => write!(f, "-"),
+            UnOperator::Stringify => write!(f, "str"),
         }
     }
 }
@@ -164,6 +359,201 @@ impl fmt::Display for Value {
             Value::Boolean(b) => write!(f, "{}", b),
             Value::Nil => write!(f, "nil"),
             Value::Callable(ref callable) => write!(f, "{}", callable),
+            Value::Class(ref class) => write!(f, "{}", class),
+            Value::Instance(ref instance) => write!(f, "{}", instance),
+            Value::List(ref list) => display_once(f, Rc::as_ptr(list) as *const (), "[...]", |f| {
+                let elements: Vec<String> = list.borrow().iter().map(|v| v.to_string()).collect();
+                write!(f, "[{}]", elements.join(", "))
+            }),
+            Value::Map(ref map) => display_once(f, Rc::as_ptr(map) as *const (), "{...}", |f| {
+                let map = map.borrow();
+                let mut keys: Vec<&MapKey> = map.keys().collect();
+                keys.sort();
+                let entries: Vec<String> = keys
+                    .into_iter()
+                    .map(|k| format!("{}: {}", k.clone().into_value(), map[k]))
+                    .collect();
+                write!(f, "{{{}}}", entries.join(", "))
+            }),
+        }
+    }
+}
+
+// Displays a list or map with `display`, unless it's already being displayed further out
+fn display_once(
+    f: &mut fmt::Formatter<'_>,
+    value: *const (),
+    repeat: &str,
+    display: impl FnOnce(&mut fmt::Formatter<'_>) -> fmt::Result,
+) -> fmt::Result {
+    if DISPLAYING.with(|displaying| displaying.borrow().contains(&value)) {
+        return write!(f, "{}", repeat);
+    }
+
+    DISPLAYING.with(|displaying| displaying.borrow_mut().push(value));
+    let result = display(f);
+    DISPLAYING.with(|displaying| displaying.borrow_mut().pop());
+    result
+}
+
+// Syntax trees are rendered as s-expressions, e.g. `(print (+ 1 (call f x)))`
+
+fn write_list<T: fmt::Display>(f: &mut fmt::Formatter<'_>, items: &[T]) -> fmt::Result {
+    for item in items {
+        write!(f, " {}", item)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExprKind::Binary {
+                lh_expr,
+                op,
+                rh_expr,
+            } => write!(f, "({} {} {})", op, lh_expr, rh_expr),
+            ExprKind::Unary { op, rh_expr } => write!(f, "({} {})", op, rh_expr),
+            ExprKind::Literal(val) => write!(f, "{}", val),
+            ExprKind::Assign { name, value, .. } => write!(f, "(= {} {})", name, value),
+            ExprKind::Variable { name, .. } => write!(f, "{}", name),
+            ExprKind::Logical {
+                lh_expr,
+                op,
+                rh_expr,
+            } => {
+                let op = match op {
+                    LogicOperator::And => "and",
+                    LogicOperator::Or => "or",
+                };
+                write!(f, "({} {} {})", op, lh_expr, rh_expr)
+            }
+            ExprKind::Call { callee, arguments } => {
+                write!(f, "(call {}", callee)?;
+                write_list(f, arguments)?;
+                write!(f, ")")
+            }
+            ExprKind::Get { object, name } => write!(f, "(. {} {})", object, name),
+            ExprKind::Set {
+                object,
+                name,
+                value,
+            } => write!(f, "(.= {} {} {})", object, name, value),
+            ExprKind::This { .. } => write!(f, "this"),
+            ExprKind::List(elements) => {
+                write!(f, "(list")?;
+                write_list(f, elements)?;
+                write!(f, ")")
+            }
+            ExprKind::Map(entries) => {
+                write!(f, "(map")?;
+                for (key, value) in entries {
+                    write!(f, " ({} {})", key, value)?;
+                }
+                write!(f, ")")
+            }
+            ExprKind::Index { object, index } => write!(f, "([] {} {})", object, index),
+            ExprKind::SetIndex {
+                object,
+                index,
+                value,
+            } => write!(f, "([]= {} {} {})", object, index, value),
+            ExprKind::Super { method, .. } => write!(f, "(super {})", method),
+            ExprKind::Lambda { parameters, body } => {
+                write!(f, "(fun ({})", parameters.join(" "))?;
+                write_list(f, body)?;
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            StmtKind::Expression(expr) => write!(f, "(; {})", expr),
+            StmtKind::Function {
+                name,
+                parameters,
+                body,
+            } => {
+                write!(f, "(fun {} ({})", name, parameters.join(" "))?;
+                write_list(f, body)?;
+                write!(f, ")")
+            }
+            StmtKind::Print(expr) => write!(f, "(print {})", expr),
+            StmtKind::Return(expr) => write!(f, "(return {})", expr),
+            StmtKind::Var {
+                name,
+                initializer: Some(initializer),
+            } => write!(f, "(var {} {})", name, initializer),
+            StmtKind::Var {
+                name,
+                initializer: None,
+            } => write!(f, "(var {})", name),
+            StmtKind::Block(statements) => {
+                write!(f, "(block")?;
+                write_list(f, statements)?;
+                write!(f, ")")
+            }
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                write!(f, "(if {} {}", condition, then_branch)?;
+                if let Some(else_branch) = else_branch {
+                    write!(f, " {}", else_branch)?;
+                }
+                write!(f, ")")
+            }
+            StmtKind::While {
+                condition,
+                body,
+                increment,
+            } => {
+                write!(f, "(while {} {}", condition, body)?;
+                if let Some(increment) = increment {
+                    write!(f, " {}", increment)?;
+                }
+                write!(f, ")")
+            }
+            StmtKind::Break => write!(f, "(break)"),
+            StmtKind::Continue => write!(f, "(continue)"),
+            StmtKind::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                write!(f, "(class {}", name)?;
+                if let Some(superclass) = superclass {
+                    write!(f, " (< {})", superclass)?;
+                }
+                write_list(f, methods)?;
+                write!(f, ")")
+            }
+            StmtKind::Throw(expr) => write!(f, "(throw {})", expr),
+            StmtKind::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                write!(f, "(try (block")?;
+                write_list(f, body)?;
+                write!(f, ")")?;
+                if let Some((name, handler)) = catch {
+                    write!(f, " (catch {}", name)?;
+                    write_list(f, handler)?;
+                    write!(f, ")")?;
+                }
+                if let Some(finally) = finally {
+                    write!(f, " (finally")?;
+                    write_list(f, finally)?;
+                    write!(f, ")")?;
+                }
+                write!(f, ")")
+            }
+            StmtKind::Import { name, path } => write!(f, "(import {} \"{}\")", name, path),
         }
     }
 }
@@ -0,0 +1,380 @@
+// Bytecode format for the VM backend, modelled on clox.
+//
+// A Chunk holds the compiled code of a single function: a flat stream of bytes where each
+// instruction is a one-byte OpCode followed by its operands. Constants, identifiers and
+// nested function prototypes live in side tables that operands index into.
+// Operands are big-endian: one byte for argument counts and flags, two bytes for table
+// indexes, stack slots and upvalues, and four bytes for jump offsets.
+
+use std::fmt::{self, Write};
+use std::rc::Rc;
+
+use super::ast::Value;
+use super::diagnostic::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum OpCode {
+    Constant, // u16 constant index
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal,     // u16 stack slot
+    SetLocal,     // u16 stack slot
+    GetGlobal,    // u16 name index
+    DefineGlobal, // u16 name index
+    SetGlobal,    // u16 name index
+    GetUpvalue,   // u16 upvalue index
+    SetUpvalue,   // u16 upvalue index
+    GetProperty,  // u16 name index
+    SetProperty,  // u16 name index
+    GetSuper,     // u16 name index
+    GetIndex,
+    SetIndex,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Stringify,
+    Print,
+    Jump,        // u32 forward offset
+    JumpIfFalse, // u32 forward offset
+    Loop,        // u32 backward offset
+    Call,        // u8 argument count
+    Closure,     // u16 function index, then (u8 is_local, u16 index) per upvalue
+    CloseUpvalue,
+    Return,
+    Class,     // u16 name index, u8 has_superclass, u16 method count
+    BuildList, // u16 element count
+    BuildMap,  // u16 entry count
+    Throw,
+    Try,        // u32 forward offset to the catch clause
+    TryFinally, // u32 forward offset to the finally clause that runs on an error
+    EndTry,
+    Rethrow,   // Raises the error a finally clause was run for again
+    DropError, // Lets go of that error instead, when the finally clause jumps elsewhere
+    Import,    // u16 name index of the module's path
+}
+
+impl OpCode {
+    const ALL: [OpCode; 48] = [
+        OpCode::Constant,
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::Pop,
+        OpCode::GetLocal,
+        OpCode::SetLocal,
+        OpCode::GetGlobal,
+        OpCode::DefineGlobal,
+        OpCode::SetGlobal,
+        OpCode::GetUpvalue,
+        OpCode::SetUpvalue,
+        OpCode::GetProperty,
+        OpCode::SetProperty,
+        OpCode::GetSuper,
+        OpCode::GetIndex,
+        OpCode::SetIndex,
+        OpCode::Equal,
+        OpCode::NotEqual,
+        OpCode::Greater,
+        OpCode::GreaterEqual,
+        OpCode::Less,
+        OpCode::LessEqual,
+        OpCode::Add,
+        OpCode::Subtract,
+        OpCode::Multiply,
+        OpCode::Divide,
+        OpCode::Not,
+        OpCode::Negate,
+        OpCode::Stringify,
+        OpCode::Print,
+        OpCode::Jump,
+        OpCode::JumpIfFalse,
+        OpCode::Loop,
+        OpCode::Call,
+        OpCode::Closure,
+        OpCode::CloseUpvalue,
+        OpCode::Return,
+        OpCode::Class,
+        OpCode::BuildList,
+        OpCode::BuildMap,
+        OpCode::Throw,
+        OpCode::Try,
+        OpCode::TryFinally,
+        OpCode::EndTry,
+        OpCode::Rethrow,
+        OpCode::DropError,
+        OpCode::Import,
+    ];
+
+    pub fn from_byte(byte: u8) -> OpCode {
+        OpCode::ALL[byte as usize]
+    }
+}
+
+impl fmt::Display for OpCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OpCode::Constant => "OP_CONSTANT",
+            OpCode::Nil => "OP_NIL",
+            OpCode::True => "OP_TRUE",
+            OpCode::False => "OP_FALSE",
+            OpCode::Pop => "OP_POP",
+            OpCode::GetLocal => "OP_GET_LOCAL",
+            OpCode::SetLocal => "OP_SET_LOCAL",
+            OpCode::GetGlobal => "OP_GET_GLOBAL",
+            OpCode::DefineGlobal => "OP_DEFINE_GLOBAL",
+            OpCode::SetGlobal => "OP_SET_GLOBAL",
+            OpCode::GetUpvalue => "OP_GET_UPVALUE",
+            OpCode::SetUpvalue => "OP_SET_UPVALUE",
+            OpCode::GetProperty => "OP_GET_PROPERTY",
+            OpCode::SetProperty => "OP_SET_PROPERTY",
+            OpCode::GetSuper => "OP_GET_SUPER",
+            OpCode::GetIndex => "OP_GET_INDEX",
+            OpCode::SetIndex => "OP_SET_INDEX",
+            OpCode::Equal => "OP_EQUAL",
+            OpCode::NotEqual => "OP_NOT_EQUAL",
+            OpCode::Greater => "OP_GREATER",
+            OpCode::GreaterEqual => "OP_GREATER_EQUAL",
+            OpCode::Less => "OP_LESS",
+            OpCode::LessEqual => "OP_LESS_EQUAL",
+            OpCode::Add => "OP_ADD",
+            OpCode::Subtract => "OP_SUBTRACT",
+            OpCode::Multiply => "OP_MULTIPLY",
+            OpCode::Divide => "OP_DIVIDE",
+            OpCode::Not => "OP_NOT",
+            OpCode::Negate => "OP_NEGATE",
+            OpCode::Stringify => "OP_STRINGIFY",
+            OpCode::Print => "OP_PRINT",
+            OpCode::Jump => "OP_JUMP",
+            OpCode::JumpIfFalse => "OP_JUMP_IF_FALSE",
+            OpCode::Loop => "OP_LOOP",
+            OpCode::Call => "OP_CALL",
+            OpCode::Closure => "OP_CLOSURE",
+            OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE",
+            OpCode::Return => "OP_RETURN",
+            OpCode::Class => "OP_CLASS",
+            OpCode::BuildList => "OP_BUILD_LIST",
+            OpCode::BuildMap => "OP_BUILD_MAP",
+            OpCode::Throw => "OP_THROW",
+            OpCode::Try => "OP_TRY",
+            OpCode::TryFinally => "OP_TRY_FINALLY",
+            OpCode::EndTry => "OP_END_TRY",
+            OpCode::Rethrow => "OP_RETHROW",
+            OpCode::DropError => "OP_DROP_ERROR",
+            OpCode::Import => "OP_IMPORT",
+        };
+        f.pad(name)
+    }
+}
+
+// A compiled function, before it's wrapped in a closure at runtime
+#[derive(Debug, Default)]
+pub struct Function {
+    pub name: Rc<str>,
+    pub parameters: Vec<Rc<str>>,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+}
+
+impl Function {
+    pub fn arity(&self) -> usize {
+        self.parameters.len()
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(fn {} ({}))", self.name, self.parameters.join(", "))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    // Source span of the syntax tree node each byte in `code` was compiled from
+    pub spans: Vec<Span>,
+    pub constants: Vec<Value>,
+    pub names: Vec<Rc<str>>,
+    pub functions: Vec<Rc<Function>>,
+}
+
+impl Chunk {
+    pub fn write(&mut self, byte: u8, span: Span) {
+        self.code.push(byte);
+        self.spans.push(span);
+    }
+
+    pub fn write_op(&mut self, op: OpCode, span: Span) {
+        self.write(op as u8, span);
+    }
+
+    pub fn write_u16(&mut self, value: u16, span: Span) {
+        self.write((value >> 8) as u8, span);
+        self.write(value as u8, span);
+    }
+
+    pub fn write_u32(&mut self, value: u32, span: Span) {
+        self.write_u16((value >> 16) as u16, span);
+        self.write_u16(value as u16, span);
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        (self.code[offset] as u16) << 8 | self.code[offset + 1] as u16
+    }
+
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        (self.read_u16(offset) as u32) << 16 | self.read_u16(offset + 2) as u32
+    }
+
+    // Identifiers are deduplicated, since the same global or property tends to be
+    // referred to many times within a function
+    pub fn add_name(&mut self, name: &Rc<str>) -> usize {
+        match self.names.iter().position(|n| n == name) {
+            Some(index) => index,
+            None => {
+                self.names.push(Rc::clone(name));
+                self.names.len() - 1
+            }
+        }
+    }
+
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+
+    pub fn add_function(&mut self, function: Function) -> usize {
+        self.functions.push(Rc::new(function));
+        self.functions.len() - 1
+    }
+}
+
+// ----------------------
+// ---- Disassembler ----
+// ----------------------
+
+// Renders a function's chunk, followed by every function nested inside it
+pub fn disassemble(function: &Function) -> String {
+    let mut output = String::new();
+    disassemble_function(function, &mut output);
+    output
+}
+
+fn disassemble_function(function: &Function, output: &mut String) {
+    let chunk = &function.chunk;
+    let _ = writeln!(output, "== {} ==", function.name);
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offset = disassemble_instruction(chunk, offset, output);
+    }
+
+    for nested in chunk.functions.iter() {
+        let _ = writeln!(output);
+        disassemble_function(nested, output);
+    }
+}
+
+// Writes a single instruction and returns the offset of the next one
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize, output: &mut String) -> usize {
+    let _ = write!(output, "{:04} ", offset);
+    let line = chunk.spans[offset].start.line;
+    if offset > 0 && line == chunk.spans[offset - 1].start.line {
+        let _ = write!(output, "   | ");
+    } else {
+        let _ = write!(output, "{:4} ", line);
+    }
+
+    let op = OpCode::from_byte(chunk.code[offset]);
+    match op {
+        OpCode::Constant => {
+            let index = chunk.read_u16(offset + 1) as usize;
+            let _ = writeln!(output, "{:<18} {:4} {}", op, index, chunk.constants[index]);
+            offset + 3
+        }
+        OpCode::GetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::SetGlobal
+        | OpCode::GetProperty
+        | OpCode::SetProperty
+        | OpCode::GetSuper
+        | OpCode::Import => {
+            let index = chunk.read_u16(offset + 1) as usize;
+            let _ = writeln!(output, "{:<18} {:4} '{}'", op, index, chunk.names[index]);
+            offset + 3
+        }
+        OpCode::Call => {
+            let _ = writeln!(output, "{:<18} {:4}", op, chunk.code[offset + 1]);
+            offset + 2
+        }
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::BuildList
+        | OpCode::BuildMap => {
+            let _ = writeln!(output, "{:<18} {:4}", op, chunk.read_u16(offset + 1));
+            offset + 3
+        }
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::Try | OpCode::TryFinally => {
+            let jump = chunk.read_u32(offset + 1) as usize;
+            let _ = writeln!(output, "{:<18} {:4} -> {}", op, offset, offset + 5 + jump);
+            offset + 5
+        }
+        OpCode::Loop => {
+            let jump = chunk.read_u32(offset + 1) as usize;
+            let _ = writeln!(output, "{:<18} {:4} -> {}", op, offset, offset + 5 - jump);
+            offset + 5
+        }
+        OpCode::Closure => {
+            let index = chunk.read_u16(offset + 1) as usize;
+            let function = &chunk.functions[index];
+            let _ = writeln!(output, "{:<18} {:4} {}", op, index, function);
+            let mut offset = offset + 3;
+            for _ in 0..function.upvalue_count {
+                let kind = if chunk.code[offset] == 1 {
+                    "local"
+                } else {
+                    "upvalue"
+                };
+                let _ = writeln!(
+                    output,
+                    "{:04}    |                    {} {}",
+                    offset,
+                    kind,
+                    chunk.read_u16(offset + 1)
+                );
+                offset += 3;
+            }
+            offset
+        }
+        OpCode::Class => {
+            let index = chunk.read_u16(offset + 1) as usize;
+            let _ = writeln!(
+                output,
+                "{:<18} {:4} '{}' (superclass: {}, methods: {})",
+                op,
+                index,
+                chunk.names[index],
+                chunk.code[offset + 3] == 1,
+                chunk.read_u16(offset + 4)
+            );
+            offset + 6
+        }
+        _ => {
+            let _ = writeln!(output, "{}", op);
+            offset + 1
+        }
+    }
+}
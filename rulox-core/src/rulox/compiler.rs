@@ -0,0 +1,953 @@
+// Compiles the parse tree into bytecode for the VM backend.
+//
+// Unlike the tree-walking Interpreter, which relies on the Resolver, the compiler tracks
+// scopes itself: locals live in stack slots of the current call frame, variables captured
+// by closures become upvalues, and anything not found in an enclosing function is global.
+
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+use super::ast::{BiOperator, Expr, ExprKind, LogicOperator, Stmt, StmtKind, UnOperator, Value};
+use super::chunk::{Chunk, Function, OpCode};
+use super::diagnostic::Span;
+use super::function::LAMBDA_NAME;
+
+#[derive(Debug, Clone)]
+pub struct CompileError {
+    pub msg: String,
+    pub span: Span,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+type CompileResult = Result<(), CompileError>;
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+struct Local {
+    name: Rc<str>,
+    // None until the variable's initializer has been compiled
+    depth: Option<usize>,
+    is_captured: bool,
+}
+
+struct Upvalue {
+    index: u16,
+    is_local: bool,
+}
+
+// Forward jumps emitted by `break` and `continue`, patched once the loop is compiled
+struct Loop {
+    scope_depth: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+// Code that `break`, `continue` and `return` have to clean up after when they jump out of it
+enum Protected {
+    // The body of a `try`, whose handler has to be removed and whose finally clause still runs
+    Try(Option<Vec<Stmt>>),
+    // A finally clause run because of an error, which has to be let go of
+    Finally,
+}
+
+struct Guard {
+    protected: Protected,
+    // How many loops were open around it, so `break` and `continue` know whether they leave it
+    loop_count: usize,
+}
+
+struct FunctionState {
+    function: Function,
+    kind: FunctionKind,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+    loops: Vec<Loop>,
+    guards: Vec<Guard>,
+}
+
+impl FunctionState {
+    fn new(name: Rc<str>, parameters: Vec<Rc<str>>, kind: FunctionKind) -> Self {
+        // Slot zero holds the function being called, or the receiver inside methods
+        let slot_zero = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            _ => "",
+        };
+        Self {
+            function: Function {
+                name,
+                parameters,
+                ..Function::default()
+            },
+            kind,
+            locals: vec![Local {
+                name: Rc::from(slot_zero),
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            loops: Vec::new(),
+            guards: Vec::new(),
+        }
+    }
+}
+
+pub struct Compiler {
+    states: Vec<FunctionState>,
+    // Span of the innermost node being compiled, which emitted bytes are attributed to
+    span: Span,
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Self {
+            states: vec![FunctionState::new(
+                Rc::from("script"),
+                Vec::new(),
+                FunctionKind::Script,
+            )],
+            span: Span::default(),
+        }
+    }
+
+    // Entry point for the compiler: the whole program becomes the body of a top-level function
+    pub fn compile(mut self, statements: &[Stmt]) -> Result<Function, CompileError> {
+        for stmt in statements {
+            self.statement(stmt)?;
+        }
+        self.emit_return();
+        Ok(self.states.pop().unwrap().function)
+    }
+
+    fn statement(&mut self, stmt: &Stmt) -> CompileResult {
+        let enclosing = mem::replace(&mut self.span, stmt.span);
+        let result = self.statement_kind(&stmt.kind);
+        self.span = enclosing;
+        result
+    }
+
+    fn statement_kind(&mut self, kind: &StmtKind) -> CompileResult {
+        match kind {
+            StmtKind::Expression(expr) => {
+                self.expression(expr)?;
+                self.emit_op(OpCode::Pop);
+            }
+            StmtKind::Print(expr) => {
+                self.expression(expr)?;
+                self.emit_op(OpCode::Print);
+            }
+            StmtKind::Var { name, initializer } => {
+                self.declare_variable(name)?;
+                match initializer {
+                    Some(expr) => self.expression(expr)?,
+                    None => self.emit_op(OpCode::Nil),
+                }
+                self.define_variable(name)?;
+            }
+            StmtKind::Function {
+                name,
+                parameters,
+                body,
+            } => {
+                self.declare_variable(name)?;
+                // A function may refer to itself, so it's usable before its body is compiled
+                self.mark_initialized();
+                self.function(name, parameters, body, FunctionKind::Function)?;
+                self.define_variable(name)?;
+            }
+            StmtKind::Block(statements) => self.block(statements)?,
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition)?;
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.statement(then_branch)?;
+                let else_jump = self.emit_jump(OpCode::Jump);
+
+                self.patch_jump(then_jump)?;
+                self.emit_op(OpCode::Pop);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch)?;
+                }
+                self.patch_jump(else_jump)?;
+            }
+            StmtKind::While {
+                condition,
+                body,
+                increment,
+            } => self.while_statement(condition, body, increment)?,
+            StmtKind::Break => {
+                let depth = self.exit_loop_scopes("Can't use 'break' outside of a loop.")?;
+                let jump = self.emit_jump(OpCode::Jump);
+                self.state().loops[depth].breaks.push(jump);
+            }
+            StmtKind::Continue => {
+                let depth = self.exit_loop_scopes("Can't use 'continue' outside of a loop.")?;
+                let jump = self.emit_jump(OpCode::Jump);
+                self.state().loops[depth].continues.push(jump);
+            }
+            StmtKind::Return(expr) => {
+                if self.state().kind == FunctionKind::Initializer {
+                    // Initializers always hand back the instance
+                    self.expression(expr)?;
+                    self.emit_op(OpCode::Pop);
+                    self.emit_op(OpCode::GetLocal);
+                    self.emit_u16(0);
+                } else {
+                    self.expression(expr)?;
+                }
+                self.return_statement()?;
+            }
+            StmtKind::Throw(expr) => {
+                self.expression(expr)?;
+                self.emit_op(OpCode::Throw);
+            }
+            StmtKind::Import { name, path } => {
+                self.declare_variable(name)?;
+                let index = self.name_index(&Rc::from(path.as_str()))?;
+                self.emit_op(OpCode::Import);
+                self.emit_u16(index);
+                self.define_variable(name)?;
+            }
+            StmtKind::Try {
+                body,
+                catch,
+                finally,
+            } => self.try_statement(body, catch, finally)?,
+            StmtKind::Class {
+                name,
+                superclass,
+                methods,
+            } => self.class_declaration(name, superclass, methods)?,
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, statements: &[Stmt]) -> CompileResult {
+        self.begin_scope();
+        for stmt in statements {
+            self.statement(stmt)?;
+        }
+        self.end_scope();
+        Ok(())
+    }
+
+    // Emits the `return` for the value on top of the stack, after running the finally
+    // clauses it leaves
+    fn return_statement(&mut self) -> CompileResult {
+        let has_finally = self
+            .state()
+            .guards
+            .iter()
+            .any(|guard| matches!(guard.protected, Protected::Try(Some(_))));
+        if !has_finally {
+            self.exit_guards(0)?;
+            self.emit_op(OpCode::Return);
+            return Ok(());
+        }
+
+        // The value waits in a hidden local while the finally clauses run
+        self.begin_scope();
+        self.add_local(Rc::from(""))?;
+        self.mark_initialized();
+        let slot = self.state().locals.len() - 1;
+        self.exit_guards(0)?;
+        self.emit_op(OpCode::GetLocal);
+        self.emit_u16(slot as u16);
+        self.emit_op(OpCode::Return);
+
+        // Nothing runs after the return, so the scope ends without popping anything
+        let state = self.state();
+        state.locals.pop();
+        state.scope_depth -= 1;
+        Ok(())
+    }
+
+    // A finally clause guards the whole try/catch: it runs after either of them finishes,
+    // and also when an error escapes them, before that error carries on unwinding
+    fn try_statement(
+        &mut self,
+        body: &[Stmt],
+        catch: &Option<(Rc<str>, Vec<Stmt>)>,
+        finally: &Option<Vec<Stmt>>,
+    ) -> CompileResult {
+        let finally = match finally {
+            Some(finally) => finally,
+            None => return self.try_catch(body, catch),
+        };
+
+        let handler = self.emit_jump(OpCode::TryFinally);
+        self.enter_guard(Protected::Try(Some(finally.clone())));
+        self.try_catch(body, catch)?;
+        self.state().guards.pop();
+        self.emit_op(OpCode::EndTry);
+        self.block(finally)?;
+        let end = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(handler)?;
+        self.enter_guard(Protected::Finally);
+        self.block(finally)?;
+        self.state().guards.pop();
+        self.emit_op(OpCode::Rethrow);
+
+        self.patch_jump(end)
+    }
+
+    fn try_catch(&mut self, body: &[Stmt], catch: &Option<(Rc<str>, Vec<Stmt>)>) -> CompileResult {
+        let (name, handler) = match catch {
+            Some(catch) => catch,
+            None => return self.block(body),
+        };
+
+        let catch_jump = self.emit_jump(OpCode::Try);
+        self.enter_guard(Protected::Try(None));
+        self.block(body)?;
+        self.state().guards.pop();
+        self.emit_op(OpCode::EndTry);
+        let end = self.emit_jump(OpCode::Jump);
+
+        // The VM pushes the caught error, right into the slot of the error variable
+        self.patch_jump(catch_jump)?;
+        self.begin_scope();
+        self.add_local(Rc::clone(name))?;
+        self.mark_initialized();
+        for stmt in handler {
+            self.statement(stmt)?;
+        }
+        self.end_scope();
+
+        self.patch_jump(end)
+    }
+
+    fn enter_guard(&mut self, protected: Protected) {
+        let loop_count = self.state().loops.len();
+        self.state().guards.push(Guard {
+            protected,
+            loop_count,
+        });
+    }
+
+    // Leaves every guard past the first `count`, innermost first, as if it finished normally.
+    // Each one stays in place while the code leaving the ones inside it is compiled, so that
+    // a finally clause which jumps elsewhere itself still leaves the ones around it.
+    fn exit_guards(&mut self, count: usize) -> CompileResult {
+        let mut exited = Vec::new();
+        while self.state().guards.len() > count {
+            let guard = self.state().guards.pop().unwrap();
+            match &guard.protected {
+                Protected::Try(finally) => {
+                    self.emit_op(OpCode::EndTry);
+                    if let Some(finally) = finally {
+                        self.block(finally)?;
+                    }
+                }
+                Protected::Finally => self.emit_op(OpCode::DropError),
+            }
+            exited.push(guard);
+        }
+        exited.reverse();
+        self.state().guards.extend(exited);
+        Ok(())
+    }
+
+    fn while_statement(
+        &mut self,
+        condition: &Expr,
+        body: &Stmt,
+        increment: &Option<Expr>,
+    ) -> CompileResult {
+        let loop_start = self.chunk().code.len();
+        self.expression(condition)?;
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+
+        let scope_depth = self.state().scope_depth;
+        self.state().loops.push(Loop {
+            scope_depth,
+            breaks: Vec::new(),
+            continues: Vec::new(),
+        });
+        self.statement(body)?;
+        let current_loop = self.state().loops.pop().unwrap();
+
+        // `continue` skips the rest of the body but still runs a for loop's increment
+        for jump in current_loop.continues {
+            self.patch_jump(jump)?;
+        }
+        if let Some(increment) = increment {
+            self.expression(increment)?;
+            self.emit_op(OpCode::Pop);
+        }
+        self.emit_loop(loop_start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.emit_op(OpCode::Pop);
+
+        // Breaking out happens after the condition was popped, so it lands past that Pop
+        for jump in current_loop.breaks {
+            self.patch_jump(jump)?;
+        }
+        Ok(())
+    }
+
+    // Pops the locals declared inside the innermost loop before jumping out of it,
+    // and returns that loop's position in the loop stack
+    fn exit_loop_scopes(&mut self, msg: &str) -> Result<usize, CompileError> {
+        let loop_count = self.state().loops.len();
+        if loop_count == 0 {
+            return Err(self.error(msg));
+        }
+        // The tries entered inside the loop are left along with it
+        let guards = &self.state().guards;
+        let outside = guards
+            .iter()
+            .position(|guard| guard.loop_count >= loop_count)
+            .unwrap_or(guards.len());
+        self.exit_guards(outside)?;
+
+        let state = self.states.last().unwrap();
+        let current_loop = state.loops.last().unwrap();
+
+        let ops: Vec<OpCode> = state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|d| d > current_loop.scope_depth))
+            .map(|local| {
+                if local.is_captured {
+                    OpCode::CloseUpvalue
+                } else {
+                    OpCode::Pop
+                }
+            })
+            .collect();
+        let depth = state.loops.len() - 1;
+
+        for op in ops {
+            self.emit_op(op);
+        }
+        Ok(depth)
+    }
+
+    fn class_declaration(
+        &mut self,
+        name: &Rc<str>,
+        superclass: &Option<Expr>,
+        methods: &[Stmt],
+    ) -> CompileResult {
+        // A local class reserves its slot up front so that methods can capture it
+        let is_local = self.state().scope_depth > 0;
+        if is_local {
+            self.declare_variable(name)?;
+            self.emit_op(OpCode::Nil);
+            self.mark_initialized();
+        }
+
+        if let Some(superclass) = superclass {
+            // Methods capture `super` from a scope wrapped around the class body
+            self.begin_scope();
+            self.expression(superclass)?;
+            self.add_local(Rc::from("super"))?;
+            self.mark_initialized();
+            self.named_variable(&Rc::from("super"))?;
+        }
+
+        for method in methods {
+            if let StmtKind::Function {
+                name,
+                parameters,
+                body,
+            } = &method.kind
+            {
+                let kind = if &**name == "init" {
+                    FunctionKind::Initializer
+                } else {
+                    FunctionKind::Method
+                };
+                self.function(name, parameters, body, kind)?;
+            }
+        }
+
+        if methods.len() > u16::MAX as usize {
+            return Err(self.error("Too many methods in one class."));
+        }
+        let name_index = self.name_index(name)?;
+        self.emit_op(OpCode::Class);
+        self.emit_u16(name_index);
+        self.emit_byte(superclass.is_some() as u8);
+        self.emit_u16(methods.len() as u16);
+
+        if is_local {
+            let slot = self.resolve_local(self.states.len() - 1, name)?.unwrap();
+            self.emit_op(OpCode::SetLocal);
+            self.emit_u16(slot);
+            self.emit_op(OpCode::Pop);
+        } else {
+            self.emit_op(OpCode::DefineGlobal);
+            self.emit_u16(name_index);
+        }
+
+        if superclass.is_some() {
+            self.end_scope();
+        }
+        Ok(())
+    }
+
+    fn function(
+        &mut self,
+        name: &Rc<str>,
+        parameters: &[Rc<str>],
+        body: &[Stmt],
+        kind: FunctionKind,
+    ) -> CompileResult {
+        self.states.push(FunctionState::new(
+            Rc::clone(name),
+            parameters.to_vec(),
+            kind,
+        ));
+        self.begin_scope();
+        for param in parameters {
+            self.add_local(Rc::clone(param))?;
+            self.mark_initialized();
+        }
+        for stmt in body {
+            self.statement(stmt)?;
+        }
+        self.emit_return();
+
+        let state = self.states.pop().unwrap();
+        let mut function = state.function;
+        function.upvalue_count = state.upvalues.len();
+
+        let index = self.chunk().add_function(function);
+        if index > u16::MAX as usize {
+            return Err(self.error("Too many functions in one chunk."));
+        }
+        self.emit_op(OpCode::Closure);
+        self.emit_u16(index as u16);
+        for upvalue in state.upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_u16(upvalue.index);
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self, expr: &Expr) -> CompileResult {
+        let enclosing = mem::replace(&mut self.span, expr.span);
+        let result = self.expression_kind(&expr.kind);
+        self.span = enclosing;
+        result
+    }
+
+    fn expression_kind(&mut self, kind: &ExprKind) -> CompileResult {
+        match kind {
+            ExprKind::Literal(value) => match value {
+                Value::Nil => self.emit_op(OpCode::Nil),
+                Value::Boolean(true) => self.emit_op(OpCode::True),
+                Value::Boolean(false) => self.emit_op(OpCode::False),
+                value => self.emit_constant(value.clone())?,
+            },
+            ExprKind::Unary { op, rh_expr } => {
+                self.expression(rh_expr)?;
+                match op {
+                    UnOperator::Minus => self.emit_op(OpCode::Negate),
+                    UnOperator::Bang => self.emit_op(OpCode::Not),
+                    UnOperator::Stringify => self.emit_op(OpCode::Stringify),
+                }
+            }
+            ExprKind::Binary {
+                lh_expr,
+                op,
+                rh_expr,
+            } => {
+                self.expression(lh_expr)?;
+                self.expression(rh_expr)?;
+                self.emit_op(match op {
+                    BiOperator::Plus => OpCode::Add,
+                    BiOperator::Minus => OpCode::Subtract,
+                    BiOperator::Star => OpCode::Multiply,
+                    BiOperator::Slash => OpCode::Divide,
+                    BiOperator::Eq => OpCode::Equal,
+                    BiOperator::NotEq => OpCode::NotEqual,
+                    BiOperator::Greater => OpCode::Greater,
+                    BiOperator::GreaterEqual => OpCode::GreaterEqual,
+                    BiOperator::Less => OpCode::Less,
+                    BiOperator::LessEqual => OpCode::LessEqual,
+                });
+            }
+            ExprKind::Logical {
+                lh_expr,
+                op,
+                rh_expr,
+            } => {
+                self.expression(lh_expr)?;
+                match op {
+                    LogicOperator::And => {
+                        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        self.emit_op(OpCode::Pop);
+                        self.expression(rh_expr)?;
+                        self.patch_jump(end_jump)?;
+                    }
+                    LogicOperator::Or => {
+                        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        let end_jump = self.emit_jump(OpCode::Jump);
+                        self.patch_jump(else_jump)?;
+                        self.emit_op(OpCode::Pop);
+                        self.expression(rh_expr)?;
+                        self.patch_jump(end_jump)?;
+                    }
+                }
+            }
+            ExprKind::Variable { name, .. } => self.named_variable(name)?,
+            ExprKind::Assign { name, value, .. } => {
+                self.expression(value)?;
+                self.set_variable(name)?;
+            }
+            ExprKind::Call {
+                callee, arguments, ..
+            } => {
+                self.expression(callee)?;
+                for argument in arguments {
+                    self.expression(argument)?;
+                }
+                if arguments.len() > u8::MAX as usize {
+                    return Err(self.error("Can't have more than 255 arguments."));
+                }
+                self.emit_op(OpCode::Call);
+                self.emit_byte(arguments.len() as u8);
+            }
+            ExprKind::Get { object, name } => {
+                self.expression(object)?;
+                let index = self.name_index(name)?;
+                self.emit_op(OpCode::GetProperty);
+                self.emit_u16(index);
+            }
+            ExprKind::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.expression(object)?;
+                self.expression(value)?;
+                let index = self.name_index(name)?;
+                self.emit_op(OpCode::SetProperty);
+                self.emit_u16(index);
+            }
+            ExprKind::This { .. } => self.named_variable(&Rc::from("this"))?,
+            ExprKind::Super { method, .. } => {
+                self.named_variable(&Rc::from("this"))?;
+                self.named_variable(&Rc::from("super"))?;
+                let index = self.name_index(method)?;
+                self.emit_op(OpCode::GetSuper);
+                self.emit_u16(index);
+            }
+            ExprKind::List(elements) => {
+                for element in elements {
+                    self.expression(element)?;
+                }
+                let count = self.count_operand(elements.len())?;
+                self.emit_op(OpCode::BuildList);
+                self.emit_u16(count);
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expression(key)?;
+                    self.expression(value)?;
+                }
+                let count = self.count_operand(entries.len())?;
+                self.emit_op(OpCode::BuildMap);
+                self.emit_u16(count);
+            }
+            ExprKind::Index { object, index } => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.emit_op(OpCode::GetIndex);
+            }
+            ExprKind::SetIndex {
+                object,
+                index,
+                value,
+            } => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.expression(value)?;
+                self.emit_op(OpCode::SetIndex);
+            }
+            ExprKind::Lambda { parameters, body } => {
+                let name = Rc::from(LAMBDA_NAME);
+                self.function(&name, parameters, body, FunctionKind::Function)?;
+            }
+        }
+        Ok(())
+    }
+
+    // ---- Variables ----
+
+    fn named_variable(&mut self, name: &Rc<str>) -> CompileResult {
+        let current = self.states.len() - 1;
+        if let Some(slot) = self.resolve_local(current, name)? {
+            self.emit_op(OpCode::GetLocal);
+            self.emit_u16(slot);
+        } else if let Some(index) = self.resolve_upvalue(current, name)? {
+            self.emit_op(OpCode::GetUpvalue);
+            self.emit_u16(index);
+        } else {
+            let index = self.name_index(name)?;
+            self.emit_op(OpCode::GetGlobal);
+            self.emit_u16(index);
+        }
+        Ok(())
+    }
+
+    fn set_variable(&mut self, name: &Rc<str>) -> CompileResult {
+        let current = self.states.len() - 1;
+        if let Some(slot) = self.resolve_local(current, name)? {
+            self.emit_op(OpCode::SetLocal);
+            self.emit_u16(slot);
+        } else if let Some(index) = self.resolve_upvalue(current, name)? {
+            self.emit_op(OpCode::SetUpvalue);
+            self.emit_u16(index);
+        } else {
+            let index = self.name_index(name)?;
+            self.emit_op(OpCode::SetGlobal);
+            self.emit_u16(index);
+        }
+        Ok(())
+    }
+
+    // Globals are late bound by name, so only locals need declaring ahead of their initializer
+    fn declare_variable(&mut self, name: &Rc<str>) -> CompileResult {
+        if self.state().scope_depth == 0 {
+            return Ok(());
+        }
+        self.add_local(Rc::clone(name))
+    }
+
+    fn define_variable(&mut self, name: &Rc<str>) -> CompileResult {
+        if self.state().scope_depth > 0 {
+            // The initializer's value is already sitting in the local's stack slot
+            self.mark_initialized();
+            return Ok(());
+        }
+        let index = self.name_index(name)?;
+        self.emit_op(OpCode::DefineGlobal);
+        self.emit_u16(index);
+        Ok(())
+    }
+
+    fn add_local(&mut self, name: Rc<str>) -> CompileResult {
+        if self.state().locals.len() > u16::MAX as usize {
+            return Err(self.error("Too many local variables in function."));
+        }
+        self.state().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+        Ok(())
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state();
+        if state.scope_depth == 0 {
+            return;
+        }
+        let depth = state.scope_depth;
+        state.locals.last_mut().unwrap().depth = Some(depth);
+    }
+
+    fn resolve_local(&self, state: usize, name: &str) -> Result<Option<u16>, CompileError> {
+        for (slot, local) in self.states[state].locals.iter().enumerate().rev() {
+            if &*local.name == name {
+                if local.depth.is_none() {
+                    return Err(self.error("Can't read local variable in its own initializer."));
+                }
+                return Ok(Some(slot as u16));
+            }
+        }
+        Ok(None)
+    }
+
+    // Looks for the variable in each enclosing function in turn, threading an upvalue
+    // through every function in between
+    fn resolve_upvalue(&mut self, state: usize, name: &str) -> Result<Option<u16>, CompileError> {
+        if state == 0 {
+            return Ok(None);
+        }
+
+        if let Some(slot) = self.resolve_local(state - 1, name)? {
+            self.states[state - 1].locals[slot as usize].is_captured = true;
+            return self.add_upvalue(state, slot, true).map(Some);
+        }
+
+        if let Some(index) = self.resolve_upvalue(state - 1, name)? {
+            return self.add_upvalue(state, index, false).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    fn add_upvalue(
+        &mut self,
+        state: usize,
+        index: u16,
+        is_local: bool,
+    ) -> Result<u16, CompileError> {
+        let upvalues = &self.states[state].upvalues;
+        if let Some(existing) = upvalues
+            .iter()
+            .position(|u| u.index == index && u.is_local == is_local)
+        {
+            return Ok(existing as u16);
+        }
+        if upvalues.len() > u16::MAX as usize {
+            return Err(self.error("Too many closure variables in function."));
+        }
+        self.states[state]
+            .upvalues
+            .push(Upvalue { index, is_local });
+        Ok((self.states[state].upvalues.len() - 1) as u16)
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state().scope_depth -= 1;
+        let depth = self.state().scope_depth;
+        while let Some(local) = self.state().locals.last() {
+            if local.depth.is_some_and(|d| d <= depth) {
+                break;
+            }
+            let op = if local.is_captured {
+                OpCode::CloseUpvalue
+            } else {
+                OpCode::Pop
+            };
+            self.state().locals.pop();
+            self.emit_op(op);
+        }
+    }
+
+    // ---- Emitting bytecode ----
+
+    fn state(&mut self) -> &mut FunctionState {
+        self.states.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state().function.chunk
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let span = self.span;
+        self.chunk().write(byte, span);
+    }
+
+    fn emit_op(&mut self, op: OpCode) {
+        let span = self.span;
+        self.chunk().write_op(op, span);
+    }
+
+    fn emit_u16(&mut self, value: u16) {
+        let span = self.span;
+        self.chunk().write_u16(value, span);
+    }
+
+    fn emit_u32(&mut self, value: u32) {
+        let span = self.span;
+        self.chunk().write_u32(value, span);
+    }
+
+    fn emit_return(&mut self) {
+        if self.state().kind == FunctionKind::Initializer {
+            self.emit_op(OpCode::GetLocal);
+            self.emit_u16(0);
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+        self.emit_op(OpCode::Return);
+    }
+
+    fn emit_constant(&mut self, value: Value) -> CompileResult {
+        let index = self.chunk().add_constant(value);
+        if index > u16::MAX as usize {
+            return Err(self.error("Too many constants in one chunk."));
+        }
+        self.emit_op(OpCode::Constant);
+        self.emit_u16(index as u16);
+        Ok(())
+    }
+
+    fn name_index(&mut self, name: &Rc<str>) -> Result<u16, CompileError> {
+        let index = self.chunk().add_name(name);
+        if index > u16::MAX as usize {
+            return Err(self.error("Too many identifiers in one chunk."));
+        }
+        Ok(index as u16)
+    }
+
+    fn count_operand(&self, count: usize) -> Result<u16, CompileError> {
+        if count > u16::MAX as usize {
+            return Err(self.error("Too many elements in literal."));
+        }
+        Ok(count as u16)
+    }
+
+    // Emits a jump with a placeholder offset and returns where that offset is
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_op(op);
+        self.emit_u32(u32::MAX);
+        self.chunk().code.len() - 4
+    }
+
+    fn patch_jump(&mut self, offset: usize) -> CompileResult {
+        let jump = self.chunk().code.len() - offset - 4;
+        if jump > u32::MAX as usize {
+            return Err(self.error("Too much code to jump over."));
+        }
+        let code = &mut self.chunk().code;
+        code[offset..offset + 4].copy_from_slice(&(jump as u32).to_be_bytes());
+        Ok(())
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) -> CompileResult {
+        self.emit_op(OpCode::Loop);
+        let offset = self.chunk().code.len() - loop_start + 4;
+        if offset > u32::MAX as usize {
+            return Err(self.error("Loop body too large."));
+        }
+        self.emit_u32(offset as u32);
+        Ok(())
+    }
+
+    fn error(&self, msg: &str) -> CompileError {
+        CompileError {
+            msg: msg.to_string(),
+            span: self.span,
+        }
+    }
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,556 @@
+// Conversions between Lox values and any Rust type that implements serde's `Serialize` or
+// `Deserialize`, so a host can pass whole structs into a script and read results back out
+// without matching on `Value` variants:
+//
+//     #[derive(Serialize, Deserialize)]
+//     struct Config { name: String, retries: u32, tags: Vec<String> }
+//
+//     session.set("config", to_value(&config)?);
+//     let config: Config = from_value(session.eval("config;")?)?;
+//
+// Rust data maps onto Lox values the way it would onto JSON: structs and maps become Lox maps,
+// sequences and tuples become lists, every kind of number becomes a Lox number (integers only
+// up to 2^53, which doubles hold exactly), and None and () become nil. An enum variant
+// without data becomes its name, one with data a map from its name to the data. Going the
+// other way, structs can be read from instances too.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::rc::Rc;
+
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+use serde::ser::{self, Serialize};
+
+use super::ast::{MapKey, Value};
+use super::session::Error;
+
+// Lox only has doubles, which can't hold every integer past 2^53. Those are refused rather
+// than rounded, since the rounded number wouldn't convert back to the same integer.
+const MAX_EXACT_INTEGER: u64 = 1 << 53;
+
+fn inexact_integer(num: impl Display) -> Error {
+    Error::Convert(format!(
+        "Integers must be within 2^53 of zero to fit in a Lox number, found {}",
+        num
+    ))
+}
+
+pub fn to_value<T: Serialize + ?Sized>(data: &T) -> Result<Value, Error> {
+    data.serialize(Serializer)
+}
+
+pub fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, Error> {
+    T::deserialize(value)
+}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(message: T) -> Self {
+        Error::Convert(message.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: Display>(message: T) -> Self {
+        Error::Convert(message.to_string())
+    }
+}
+
+fn list(elements: Vec<Value>) -> Value {
+    Value::List(Rc::new(RefCell::new(elements)))
+}
+
+fn map(entries: HashMap<MapKey, Value>) -> Value {
+    Value::Map(Rc::new(RefCell::new(entries)))
+}
+
+fn string(s: &str) -> Value {
+    Value::String(Box::new(s.to_owned()))
+}
+
+// An enum variant carrying data is wrapped in a map from the variant's name to the data
+fn variant(name: &str, data: Value) -> Value {
+    let mut entries = HashMap::new();
+    entries.insert(MapKey::String(Box::new(name.to_owned())), data);
+    map(entries)
+}
+
+// ---------------------------
+// ----- Rust data to Lox -----
+// ---------------------------
+
+struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = Value;
+    type Error = Error;
+
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeVariant<SerializeList>;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeMap;
+    type SerializeStructVariant = SerializeVariant<SerializeMap>;
+
+    fn serialize_bool(self, b: bool) -> Result<Value, Error> {
+        Ok(Value::Boolean(b))
+    }
+
+    fn serialize_i8(self, num: i8) -> Result<Value, Error> {
+        self.serialize_f64(num as f64)
+    }
+
+    fn serialize_i16(self, num: i16) -> Result<Value, Error> {
+        self.serialize_f64(num as f64)
+    }
+
+    fn serialize_i32(self, num: i32) -> Result<Value, Error> {
+        self.serialize_f64(num as f64)
+    }
+
+    fn serialize_i64(self, num: i64) -> Result<Value, Error> {
+        if num.unsigned_abs() > MAX_EXACT_INTEGER {
+            return Err(inexact_integer(num));
+        }
+        self.serialize_f64(num as f64)
+    }
+
+    fn serialize_u8(self, num: u8) -> Result<Value, Error> {
+        self.serialize_f64(num as f64)
+    }
+
+    fn serialize_u16(self, num: u16) -> Result<Value, Error> {
+        self.serialize_f64(num as f64)
+    }
+
+    fn serialize_u32(self, num: u32) -> Result<Value, Error> {
+        self.serialize_f64(num as f64)
+    }
+
+    fn serialize_u64(self, num: u64) -> Result<Value, Error> {
+        if num > MAX_EXACT_INTEGER {
+            return Err(inexact_integer(num));
+        }
+        self.serialize_f64(num as f64)
+    }
+
+    fn serialize_f32(self, num: f32) -> Result<Value, Error> {
+        self.serialize_f64(num as f64)
+    }
+
+    fn serialize_f64(self, num: f64) -> Result<Value, Error> {
+        Ok(Value::Number(num))
+    }
+
+    fn serialize_char(self, c: char) -> Result<Value, Error> {
+        Ok(string(&c.to_string()))
+    }
+
+    fn serialize_str(self, s: &str) -> Result<Value, Error> {
+        Ok(string(s))
+    }
+
+    fn serialize_bytes(self, bytes: &[u8]) -> Result<Value, Error> {
+        let elements = bytes.iter().map(|&b| Value::Number(b as f64)).collect();
+        Ok(list(elements))
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, data: &T) -> Result<Value, Error> {
+        data.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        Ok(string(variant))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        data: &T,
+    ) -> Result<Value, Error> {
+        data.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        name: &'static str,
+        data: &T,
+    ) -> Result<Value, Error> {
+        Ok(variant(name, to_value(data)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeList, Error> {
+        Ok(SerializeList {
+            elements: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeList, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        name: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<SerializeList>, Error> {
+        Ok(SerializeVariant {
+            name,
+            data: self.serialize_seq(Some(len))?,
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeMap, Error> {
+        Ok(SerializeMap {
+            entries: HashMap::new(),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeMap, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        name: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<SerializeMap>, Error> {
+        Ok(SerializeVariant {
+            name,
+            data: self.serialize_map(Some(len))?,
+        })
+    }
+}
+
+struct SerializeList {
+    elements: Vec<Value>,
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, data: &T) -> Result<(), Error> {
+        self.elements.push(to_value(data)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(list(self.elements))
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, data: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, data)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, data: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, data)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+struct SerializeMap {
+    entries: HashMap<MapKey, Value>,
+    // Serde hands over each key before its value
+    key: Option<MapKey>,
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        let key = to_value(key)?;
+        match MapKey::from_value(&key) {
+            Ok(key) => self.key = Some(key),
+            Err(_) => {
+                return Err(Error::Convert(format!(
+                    "Map keys must be strings, numbers, booleans or nil, found {}",
+                    key
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, data: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .expect("serialize_value called before serialize_key");
+        self.entries.insert(key, to_value(data)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(map(self.entries))
+    }
+}
+
+impl ser::SerializeStruct for SerializeMap {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        name: &'static str,
+        data: &T,
+    ) -> Result<(), Error> {
+        let key = MapKey::String(Box::new(name.to_owned()));
+        self.entries.insert(key, to_value(data)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(map(self.entries))
+    }
+}
+
+struct SerializeVariant<S> {
+    name: &'static str,
+    data: S,
+}
+
+impl ser::SerializeTupleVariant for SerializeVariant<SerializeList> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, data: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(&mut self.data, data)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(variant(self.name, list(self.data.elements)))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeVariant<SerializeMap> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        name: &'static str,
+        data: &T,
+    ) -> Result<(), Error> {
+        ser::SerializeStruct::serialize_field(&mut self.data, name, data)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(variant(self.name, map(self.data.entries)))
+    }
+}
+
+// ---------------------------
+// ----- Lox to Rust data -----
+// ---------------------------
+
+impl<'de> IntoDeserializer<'de, Error> for Value {
+    type Deserializer = Value;
+
+    fn into_deserializer(self) -> Value {
+        self
+    }
+}
+
+// Entries are handed out in key order, the same order Lox prints maps in
+fn visit_entries<'de, V: Visitor<'de>>(
+    mut entries: Vec<(MapKey, Value)>,
+    visitor: V,
+) -> Result<V::Value, Error> {
+    entries.sort_by(|(left, _), (right, _)| left.cmp(right));
+    let entries = entries
+        .into_iter()
+        .map(|(key, value)| (key.into_value(), value));
+    let mut access = MapDeserializer::new(entries);
+    let data = visitor.visit_map(&mut access)?;
+    access.end()?;
+    Ok(data)
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::Nil => visitor.visit_unit(),
+            Value::Boolean(b) => visitor.visit_bool(b),
+            // Whole numbers are offered as integers, so they can be read into integer types
+            Value::Number(num)
+                if num.fract() == 0.0 && (i64::MIN as f64..i64::MAX as f64).contains(&num) =>
+            {
+                visitor.visit_i64(num as i64)
+            }
+            Value::Number(num) => visitor.visit_f64(num),
+            Value::String(s) => visitor.visit_string(*s),
+            Value::List(list) => {
+                let elements = list.borrow().clone();
+                let mut access = SeqDeserializer::new(elements.into_iter());
+                let data = visitor.visit_seq(&mut access)?;
+                access.end()?;
+                Ok(data)
+            }
+            Value::Map(map) => {
+                let entries = map
+                    .borrow()
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                visit_entries(entries, visitor)
+            }
+            Value::Instance(instance) => {
+                let entries = instance
+                    .fields()
+                    .into_iter()
+                    .map(|(name, value)| (MapKey::String(Box::new(name.to_string())), value))
+                    .collect();
+                visit_entries(entries, visitor)
+            }
+            value @ Value::Callable(_) | value @ Value::Class(_) => Err(Error::Convert(format!(
+                "Functions and classes can't be converted to Rust data, found {}",
+                value
+            ))),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::Nil => visitor.visit_none(),
+            value => visitor.visit_some(value),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // Reads back the enums written by `to_value`: a variant's name, or a map with a single
+    // entry from its name to its data
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let entry = match &self {
+            Value::String(name) => return visitor.visit_enum((**name).clone().into_deserializer()),
+            Value::Map(map) if map.borrow().len() == 1 => map
+                .borrow()
+                .iter()
+                .next()
+                .map(|(key, data)| (key.clone(), data.clone())),
+            _ => None,
+        };
+
+        match entry {
+            Some((MapKey::String(name), data)) => {
+                visitor.visit_enum(EnumAccess { name: *name, data })
+            }
+            _ => Err(Error::Convert(format!(
+                "Expected an enum variant's name or a map from it to its data, found {}",
+                self
+            ))),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct EnumAccess {
+    name: String,
+    data: Value,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumAccess {
+    type Error = Error;
+    type Variant = Value;
+
+    fn variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<(S::Value, Value), Error> {
+        let variant = seed.deserialize(self.name.into_deserializer())?;
+        Ok((variant, self.data))
+    }
+}
+
+// The data of an enum variant that was written as a map entry
+impl<'de> de::VariantAccess<'de> for Value {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        de::Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<S::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
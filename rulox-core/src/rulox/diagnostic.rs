@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// The stage of the pipeline that raised the diagnostic
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Phase {
+    Scan,
+    Parse,
+    Resolve,
+    Compile,
+    Runtime,
+}
+
+// A point between two characters of the source: `offset` counts bytes from the start,
+// while `line` and `col` (both starting at 1) locate the character that follows it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+// The source range `start..end` covered by a token or syntax tree node
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    pub fn new(start: Position, end: Position) -> Self {
+        Self { start, end }
+    }
+
+    // The smallest span covering both `self` and `other`
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start, other.end)
+    }
+}
+
+// A call that was still running when a runtime error was raised.
+// Calls made by a host application through a Session have no call site.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StackFrame {
+    pub function: String,
+    pub call_site: Option<Span>,
+}
+
+// Every problem found in a program, from an unexpected character to an uncaught
+// runtime error, is reported as a Diagnostic through Rulox::diagnostics.
+// The span is missing when the phase has no position to point to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub phase: Phase,
+    pub message: String,
+    pub span: Option<Span>,
+    // Innermost call first; only runtime errors raised inside a function have one
+    pub backtrace: Vec<StackFrame>,
+}
+
+impl Diagnostic {
+    pub fn error(phase: Phase, message: String, span: Option<Span>) -> Self {
+        Self {
+            severity: Severity::Error,
+            phase,
+            message,
+            span,
+            backtrace: Vec::new(),
+        }
+    }
+
+    pub fn warning(phase: Phase, message: String, span: Option<Span>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            phase,
+            message,
+            span,
+            backtrace: Vec::new(),
+        }
+    }
+
+    pub fn with_backtrace(mut self, backtrace: Vec<StackFrame>) -> Self {
+        self.backtrace = backtrace;
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Scan => write!(f, "Scan"),
+            Phase::Parse => write!(f, "Parse"),
+            Phase::Resolve => write!(f, "Resolve"),
+            Phase::Compile => write!(f, "Compile"),
+            Phase::Runtime => write!(f, "Runtime"),
+        }
+    }
+}
+
+// Deep recursion can leave thousands of frames, only the innermost ones are rendered
+const MAX_RENDERED_FRAMES: usize = 10;
+
+// e.g. "[Parse error @ 3:14] Expect ';' after value." or "[Runtime error] Undefined variable 'x'",
+// followed by one line per frame of the backtrace
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{} {}", self.phase, self.severity)?;
+        if let Some(span) = self.span {
+            write!(f, " @ {}:{}", span.start.line, span.start.col)?;
+        }
+        write!(f, "] {}", self.message)?;
+
+        for frame in self.backtrace.iter().take(MAX_RENDERED_FRAMES) {
+            write!(f, "\n    in {}()", frame.function)?;
+            if let Some(span) = frame.call_site {
+                write!(f, " called at {}:{}", span.start.line, span.start.col)?;
+            }
+        }
+        if self.backtrace.len() > MAX_RENDERED_FRAMES {
+            let hidden = self.backtrace.len() - MAX_RENDERED_FRAMES;
+            write!(f, "\n    ... and {} more", hidden)?;
+        }
+        Ok(())
+    }
+}
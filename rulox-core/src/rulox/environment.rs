@@ -9,97 +9,127 @@ use super::interpreter::RuntimeError;
 
 pub type EnvCell = Rc<RefCell<Environment>>;
 
+// The variables of a global scope by name. Every module has globals of its own, the main
+// script included, which are shared with the namespace the module is imported as.
+pub type Globals = Rc<RefCell<HashMap<Rc<str>, Value>>>;
+
+// Local scopes store their variables in declaration order, so that the resolver can
+// hand the interpreter a (depth, slot) pair for each reference. Only the global scope
+// (the one without an enclosing environment) keeps its variables by name.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Environment {
-    values: HashMap<Rc<str>, Value>,
+    values: Option<Globals>,
+    slots: Vec<Value>,
     enclosing: Option<EnvCell>,
 }
 
 impl Environment {
     pub fn new(parent: Option<EnvCell>) -> Environment {
         Environment {
-            values: HashMap::new(),
+            values: parent.is_none().then(Globals::default),
+            slots: Vec::new(),
             enclosing: parent,
         }
     }
 
+    // A global scope holding `globals`
+    pub fn with_globals(globals: Globals) -> Environment {
+        Environment {
+            values: Some(globals),
+            slots: Vec::new(),
+            enclosing: None,
+        }
+    }
+
+    // Locals take the next free slot, which matches the order the resolver declared them in
     pub fn define(&mut self, name: Rc<str>, value: Value) {
-        self.values.insert(name, value);
+        match &self.values {
+            Some(values) => {
+                values.borrow_mut().insert(name, value);
+            }
+            None => self.slots.push(value),
+        }
     }
 
-    pub fn get(&self, name: &str) -> Result<Value, RuntimeError> {
-        // To pass a reference to Rc<name>, we need to borrow its dereferenced form -> &*name
-        match self.values.get(&*name) {
-            Some(val) => Ok(val.clone()),
-            None => match &self.enclosing {
-                Some(enclosing) => Ok(enclosing.borrow().get(name)?),
-                None => Err(RuntimeError::UndefinedError {
-                    name: name.to_owned(),
-                }),
-            },
+    // Names of the variables defined in the global scope; locals are only known by slot
+    pub fn names(&self) -> Vec<Rc<str>> {
+        match &self.values {
+            Some(values) => values.borrow().keys().cloned().collect(),
+            None => Vec::new(),
         }
     }
 
-    // Need to re-implement, used for checking scope depths
-    // pub fn get_at(&self, distance: &usize, name: &str) -> Result<Value, RuntimeError> {
-    //     if *distance > 0 {
-    //         Ok(self
-    //             .ancestor(distance)
-    //             .unwrap()
-    //             .borrow()
-    //             .values
-    //             .get(name)
-    //             .expect(&format!("Undefined variable '{}'", name))
-    //             .clone())
-    //     } else {
-    //         Ok(self
-    //             .values
-    //             .get(name)
-    //             .expect(&format!("Undefined variable '{}'", name))
-    //             .clone())
-    //     }
-    // }
+    pub fn get(&self, name: &str) -> Result<Value, RuntimeError> {
+        let value = self
+            .values
+            .as_ref()
+            .and_then(|values| values.borrow().get(name).cloned());
+        value.ok_or_else(|| RuntimeError::UndefinedError {
+            name: name.to_owned(),
+        })
+    }
 
-    pub fn assign(&mut self, name: Rc<str>, value: Value) -> Result<(), RuntimeError> {
-        if self.values.contains_key(&name) {
-            self.values.insert(name, value);
-            Ok(())
+    // A slot can still be missing if the variable is read before its declaration has run,
+    // e.g. in its own initializer, which the resolver only warns about
+    pub fn get_at(&self, distance: &usize, slot: usize, name: &str) -> Result<Value, RuntimeError> {
+        let value = if *distance > 0 {
+            self.ancestor(distance).borrow().slots.get(slot).cloned()
         } else {
-            match &self.enclosing {
-                Some(enclosing) => enclosing.borrow_mut().assign(name, value),
-                None => Err(RuntimeError::UndefinedError {
-                    name: name.to_string(),
-                }),
+            self.slots.get(slot).cloned()
+        };
+
+        value.ok_or_else(|| RuntimeError::UndefinedError {
+            name: name.to_owned(),
+        })
+    }
+
+    pub fn assign(&mut self, name: Rc<str>, value: Value) -> Result<(), RuntimeError> {
+        match &self.values {
+            Some(values) if values.borrow().contains_key(&name) => {
+                values.borrow_mut().insert(name, value);
+                Ok(())
             }
+            _ => Err(RuntimeError::UndefinedError {
+                name: name.to_string(),
+            }),
         }
     }
 
-    pub fn assign_at(
-        &mut self,
-        distance: &usize,
-        name: Rc<str>,
-        value: Value,
-    ) -> Result<(), RuntimeError> {
+    pub fn assign_at(&mut self, distance: &usize, slot: usize, value: Value) {
         if *distance > 0 {
-            self.ancestor(distance)
-                .unwrap()
-                .borrow_mut()
-                .values
-                .insert(name, value);
+            self.ancestor(distance).borrow_mut().slots[slot] = value;
         } else {
-            self.values.insert(name, value);
+            self.slots[slot] = value;
         }
-        Ok(())
     }
 
-    fn ancestor(&self, distance: &usize) -> Option<EnvCell> {
-        let parent = self.enclosing.clone()?;
-        let mut environment = Rc::clone(&parent);
+    // The resolver guarantees that a resolved local is never further out than the
+    // number of enclosing scopes, so running out of parents is a bug
+    fn ancestor(&self, distance: &usize) -> EnvCell {
+        let mut environment = Rc::clone(self.enclosing.as_ref().expect("Missing ancestor scope."));
         for _ in 1..*distance {
-            let parent = environment.borrow().enclosing.clone()?;
-            environment = Rc::clone(&parent);
+            let parent = Rc::clone(
+                environment
+                    .borrow()
+                    .enclosing
+                    .as_ref()
+                    .expect("Missing ancestor scope."),
+            );
+            environment = parent;
+        }
+        environment
+    }
+
+    // The global scope `environment` ends in, i.e. that of the module it belongs to
+    pub fn global(environment: &EnvCell) -> EnvCell {
+        let mut environment = Rc::clone(environment);
+        loop {
+            let enclosing = environment.borrow().enclosing.clone();
+            match enclosing {
+                Some(enclosing) => environment = enclosing,
+                None => return environment,
+            }
         }
-        Some(environment)
     }
 
     pub fn into_cell(self) -> EnvCell {
@@ -109,11 +139,20 @@ impl Environment {
 
 impl fmt::Display for Environment {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let output_values: Vec<String> = self
-            .values
-            .iter()
-            .map(|(k, v)| format!("'{}':{}", k, v))
-            .collect();
+        let mut output_values: Vec<String> = match &self.values {
+            Some(values) => values
+                .borrow()
+                .iter()
+                .map(|(k, v)| format!("'{}':{}", k, v))
+                .collect(),
+            None => Vec::new(),
+        };
+        output_values.extend(
+            self.slots
+                .iter()
+                .enumerate()
+                .map(|(i, v)| format!("#{}:{}", i, v)),
+        );
         write!(f, "{{ {} }}", output_values.join("; "))
     }
 }
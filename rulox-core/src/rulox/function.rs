@@ -1,76 +1,133 @@
-use super::ast::{Stmt, Value};
+use super::ast::{MapKey, Stmt, Value};
 use super::environment::Environment;
 use super::interpreter::{Interpreter, RuntimeError};
 use serde::{Deserialize, Serialize};
+use std::any::Any;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt;
+use std::mem;
 use std::rc::Rc;
-// use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// What anonymous functions are called when they're printed or show up in a backtrace
+pub const LAMBDA_NAME: &str = "lambda";
+
+// The class of the values that runtime errors are caught as
+pub const ERROR_CLASS: &str = "Error";
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum FunctionType {
     None,
     Function,
+    Initializer,
+    Method,
 }
 
-// pub trait LoxCallable: fmt::Debug {
-//     fn call(&self, interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError>;
-//     fn arity(&self) -> usize;
-//     fn name(&self) -> Rc<str>;
-//     fn parameters(&self) -> Vec<Rc<str>>;
-//     fn body(&self) -> Vec<Stmt>;
-// }
+#[derive(Clone, Copy, PartialEq)]
+pub enum ClassType {
+    None,
+    Class,
+    Subclass,
+}
+
+// Anything that can be stored in Value::Callable and invoked from Lox code,
+// whether it's a user-defined LoxFunction or a native implemented in Rust
+pub trait LoxCallable: fmt::Debug + fmt::Display {
+    fn call(&self, interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError>;
+    fn arity(&self) -> usize;
+    fn name(&self) -> Rc<str>;
+    // Lets the bytecode VM recognize its own closures behind a Value::Callable
+    fn as_any(&self) -> &dyn Any;
+}
 
-// impl fmt::Display for dyn LoxCallable {
-//     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-//         write!(f, "<fn {} ({:?})>", self.name(), self.parameters())
-//     }
-// }
+// Callables that can be stored on a LoxClass, i.e. functions from either backend
+pub trait LoxMethod: LoxCallable {
+    // Produces a copy of the method where `this` refers to the given instance
+    fn bind(&self, instance: Rc<LoxInstance>) -> Rc<dyn LoxCallable>;
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoxFunction {
     name: Rc<str>,
     closure: Rc<RefCell<Environment>>,
+    // The global scope of the module the function was defined in
+    globals: Rc<RefCell<Environment>>,
     parameters: Vec<Rc<str>>,
-    body: Vec<Stmt>,
+    // Shared by every copy of a method that bind() makes
+    body: Rc<[Stmt]>,
+    is_initializer: bool,
 }
 
 impl LoxFunction {
     pub fn new(
         name: Rc<str>,
         parameters: Vec<Rc<str>>,
-        body: Vec<Stmt>,
+        body: Rc<[Stmt]>,
         closure: Rc<RefCell<Environment>>,
+        is_initializer: bool,
     ) -> Self {
         Self {
             name,
+            globals: Environment::global(&closure),
             closure,
             parameters,
             body,
+            is_initializer,
         }
     }
-    pub fn call(
-        &self,
-        interpreter: &mut Interpreter,
-        args: Vec<Value>,
-    ) -> Result<Value, RuntimeError> {
+}
+
+impl LoxMethod for LoxFunction {
+    // Wraps the method's closure in a new environment where `this` refers to the instance
+    fn bind(&self, instance: Rc<LoxInstance>) -> Rc<dyn LoxCallable> {
+        let mut env = Environment::new(Some(Rc::clone(&self.closure)));
+        env.define(Rc::from("this"), Value::Instance(instance));
+        Rc::new(LoxFunction::new(
+            Rc::clone(&self.name),
+            self.parameters.clone(),
+            Rc::clone(&self.body),
+            env.into_cell(),
+            self.is_initializer,
+        ))
+    }
+}
+
+impl LoxCallable for LoxFunction {
+    fn call(&self, interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
         let mut env = Environment::new(Some(Rc::clone(&self.closure)));
         for (param, argument) in self.parameters.iter().zip(args.iter()) {
             env.define(param.to_owned(), argument.clone());
         }
 
+        // Global variables are looked up in the function's own module, not the caller's
+        let globals = mem::replace(&mut interpreter.globals, Rc::clone(&self.globals));
         let result = interpreter.execute_block(&self.body, env.into_cell());
+        interpreter.globals = globals;
 
+        // An initializer always hands back the instance, even on an early `return;`
         match result {
+            // `this` is the only variable in the scope created by bind()
+            Ok(()) | Err(RuntimeError::Return(_)) if self.is_initializer => {
+                self.closure.borrow().get_at(&0, 0, "this")
+            }
             Ok(()) => Ok(Value::Nil),
             Err(RuntimeError::Return(val)) => Ok(val),
             Err(e) => Err(e),
         }
     }
 
-    pub fn arity(&self) -> usize {
+    fn arity(&self) -> usize {
         self.parameters.len()
     }
+
+    fn name(&self) -> Rc<str> {
+        Rc::clone(&self.name)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 impl fmt::Display for LoxFunction {
@@ -79,70 +136,238 @@ impl fmt::Display for LoxFunction {
     }
 }
 
-// #[derive(Debug, Serialize, Deserialize)]
-// pub struct LoxClass {
-//     name: Rc<str>,
-// }
-
-// impl LoxClass {
-//     pub fn new(name: Rc<str>) -> Self {
-//         Self { name }
-//     }
-//     fn call(
-//         &self,
-//         _interpreter: &mut Interpreter,
-//         _args: Vec<Value>,
-//     ) -> Result<Value, RuntimeError> {
-//         Ok(Value::Nil)
-//     }
-//     fn arity(&self) -> usize {
-//         0
-//     }
-//     fn name(&self) -> Rc<str> {
-//         Rc::clone(&self.name)
-//     }
-
-//     fn body(&self) -> Vec<Stmt> {
-//         vec![Stmt::Expression(Expr::Literal(Value::Nil))]
-//     }
-
-//     fn parameters(&self) -> Vec<Rc<str>> {
-//         vec![Rc::clone(&self.name())]
-//     }
-// }
-
-// impl fmt::Display for LoxClass {
-//     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-//         write!(f, "<class {}>", self.name)
-//     }
-// }
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoxClass {
+    name: Rc<str>,
+    superclass: Option<Rc<LoxClass>>,
+    #[serde(skip)]
+    methods: HashMap<Rc<str>, Rc<dyn LoxMethod>>,
+}
+
+impl LoxClass {
+    pub fn new(
+        name: Rc<str>,
+        superclass: Option<Rc<LoxClass>>,
+        methods: HashMap<Rc<str>, Rc<dyn LoxMethod>>,
+    ) -> Self {
+        Self {
+            name,
+            superclass,
+            methods,
+        }
+    }
+
+    // Calling a class creates a new instance and runs its initializer, if any
+    pub fn call(
+        self: &Rc<Self>,
+        interpreter: &mut Interpreter,
+        args: Vec<Value>,
+    ) -> Result<Value, RuntimeError> {
+        let instance = Rc::new(LoxInstance::new(Rc::clone(self)));
+        if let Some(initializer) = self.find_method("init") {
+            initializer
+                .bind(Rc::clone(&instance))
+                .call(interpreter, args)?;
+        }
+        Ok(Value::Instance(instance))
+    }
+
+    pub fn name(&self) -> Rc<str> {
+        Rc::clone(&self.name)
+    }
+
+    pub fn arity(&self) -> usize {
+        match self.find_method("init") {
+            Some(initializer) => initializer.arity(),
+            None => 0,
+        }
+    }
+
+    // Methods not defined on the class itself are looked up along the superclass chain
+    pub fn find_method(&self, name: &str) -> Option<Rc<dyn LoxMethod>> {
+        match self.methods.get(name) {
+            Some(method) => Some(Rc::clone(method)),
+            None => self.superclass.as_ref()?.find_method(name),
+        }
+    }
+}
+
+impl fmt::Display for LoxClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(class {})", self.name)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoxInstance {
+    class: Rc<LoxClass>,
+    fields: Rc<RefCell<HashMap<Rc<str>, Value>>>,
+}
+
+impl LoxInstance {
+    pub fn new(class: Rc<LoxClass>) -> Self {
+        Self::with_fields(class, Rc::default())
+    }
+
+    // An instance whose fields live in a map shared with its creator, e.g. a module
+    // namespace, which reads and writes the module's globals
+    pub fn with_fields(class: Rc<LoxClass>, fields: Rc<RefCell<HashMap<Rc<str>, Value>>>) -> Self {
+        Self { class, fields }
+    }
+
+    // Fields shadow methods; methods are bound to the instance on access
+    pub fn get(self: &Rc<Self>, name: &str) -> Result<Value, RuntimeError> {
+        if let Some(value) = self.fields.borrow().get(name) {
+            return Ok(value.clone());
+        }
+
+        match self.class.find_method(name) {
+            Some(method) => Ok(Value::Callable(method.bind(Rc::clone(self)))),
+            None => Err(RuntimeError::PropertyError {
+                name: name.to_owned(),
+            }),
+        }
+    }
+
+    pub fn set(&self, name: Rc<str>, value: Value) {
+        self.fields.borrow_mut().insert(name, value);
+    }
+
+    pub fn class_name(&self) -> Rc<str> {
+        self.class.name()
+    }
+
+    // A snapshot of the fields set on the instance so far, without its methods
+    pub fn fields(&self) -> HashMap<Rc<str>, Value> {
+        self.fields.borrow().clone()
+    }
+}
+
+impl fmt::Display for LoxInstance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({} instance)", self.class.name)
+    }
+}
 
 // ------------------------------
 // ---- Lox Standard Library ----
 // ------------------------------
 
-// #[derive(Debug)]
-// pub struct Clock;
-
-// impl LoxCallable for Clock {
-//     fn call(
-//         &self,
-//         _interpreter: &mut Interpreter,
-//         _args: Vec<Value>,
-//     ) -> Result<Value, RuntimeError> {
-//         Ok(Value::Number(
-//             SystemTime::now()
-//                 .duration_since(UNIX_EPOCH)
-//                 .expect("Could not retrieve time.")
-//                 .as_millis() as f64,
-//         ))
-//     }
-
-//     fn arity(&self) -> usize {
-//         0
-//     }
-
-//     fn name(&self) -> Rc<str> {
-//         Rc::from("clock")
-//     }
-// }
+// What a native is handed by the backend running it, to call back into Lox code,
+// e.g. a function it was passed as an argument
+pub trait NativeContext {
+    fn call(&mut self, callee: Value, args: Vec<Value>) -> Result<Value, RuntimeError>;
+}
+
+pub type NativeFn = dyn Fn(&mut dyn NativeContext, Vec<Value>) -> Result<Value, RuntimeError>;
+
+// A function implemented in Rust, registered by name through Interpreter::register_native
+pub struct NativeFunction {
+    name: Rc<str>,
+    arity: usize,
+    function: Box<NativeFn>,
+}
+
+impl NativeFunction {
+    pub fn new<F>(name: &str, arity: usize, function: F) -> Self
+    where
+        F: Fn(&mut dyn NativeContext, Vec<Value>) -> Result<Value, RuntimeError> + 'static,
+    {
+        Self {
+            name: Rc::from(name),
+            arity,
+            function: Box::new(function),
+        }
+    }
+
+    pub fn invoke(
+        &self,
+        context: &mut dyn NativeContext,
+        args: Vec<Value>,
+    ) -> Result<Value, RuntimeError> {
+        (self.function)(context, args)
+    }
+}
+
+impl LoxCallable for NativeFunction {
+    fn call(&self, interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        self.invoke(interpreter, args)
+    }
+
+    fn arity(&self) -> usize {
+        self.arity
+    }
+
+    fn name(&self) -> Rc<str> {
+        Rc::clone(&self.name)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NativeFunction")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish()
+    }
+}
+
+impl fmt::Display for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(native fn {})", self.name)
+    }
+}
+
+// Every native in this list is defined in the globals of a new Interpreter
+pub fn standard_library() -> Vec<NativeFunction> {
+    vec![
+        NativeFunction::new("clock", 0, |_, _| {
+            Ok(Value::Number(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Could not retrieve time.")
+                    .as_millis() as f64,
+            ))
+        }),
+        NativeFunction::new("len", 1, |_, args| match &args[0] {
+            Value::List(list) => Ok(Value::Number(list.borrow().len() as f64)),
+            Value::Map(map) => Ok(Value::Number(map.borrow().len() as f64)),
+            Value::String(s) => Ok(Value::Number(s.chars().count() as f64)),
+            val => Err(RuntimeError::type_error(
+                val,
+                "Expected list, map or string.",
+            )),
+        }),
+        NativeFunction::new("push", 2, |_, args| match &args[0] {
+            Value::List(list) => {
+                list.borrow_mut().push(args[1].clone());
+                Ok(Value::Nil)
+            }
+            val => Err(RuntimeError::type_error(val, "Expected list.")),
+        }),
+        // Popping from an empty list gives back nil rather than an error
+        NativeFunction::new("pop", 1, |_, args| match &args[0] {
+            Value::List(list) => Ok(list.borrow_mut().pop().unwrap_or(Value::Nil)),
+            val => Err(RuntimeError::type_error(val, "Expected list.")),
+        }),
+        NativeFunction::new("keys", 1, |_, args| match &args[0] {
+            Value::Map(map) => {
+                let mut keys: Vec<MapKey> = map.borrow().keys().cloned().collect();
+                keys.sort();
+                let keys = keys.into_iter().map(MapKey::into_value).collect();
+                Ok(Value::List(Rc::new(RefCell::new(keys))))
+            }
+            val => Err(RuntimeError::type_error(val, "Expected map.")),
+        }),
+        NativeFunction::new("has", 2, |_, args| match &args[0] {
+            Value::Map(map) => Ok(Value::Boolean(
+                map.borrow().contains_key(&MapKey::from_value(&args[1])?),
+            )),
+            val => Err(RuntimeError::type_error(val, "Expected map.")),
+        }),
+    ]
+}
@@ -1,56 +1,144 @@
 // use super::ast;
-use crate::rulox::ast::Expr::*;
-use crate::rulox::ast::{BiOperator, Expr, LogicOperator, Stmt, UnOperator, Value};
-use crate::rulox::environment::Environment;
+use crate::rulox::ast::ExprKind::*;
+use crate::rulox::ast::{
+    BiOperator, Expr, ExprId, ExprKind, LogicOperator, MapKey, Stmt, StmtKind, UnOperator, Value,
+};
+use crate::rulox::diagnostic::{Diagnostic, Phase, Span, StackFrame};
+use crate::rulox::environment::{Environment, Globals};
 use crate::rulox::function::*;
+use crate::rulox::modules::{self, import_error, Import, ModulesCell};
+use crate::rulox::output::{OutputCell, Writer};
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt;
+use std::io;
+use std::mem;
 use std::rc::Rc;
 pub type EnvCell = Rc<RefCell<Environment>>;
 
+// How many calls can be nested before a script is stopped with a stack overflow
+pub const MAX_CALL_DEPTH: usize = 1024;
+
+// Each Lox call nests several Rust calls, whose frames take tens of kilobytes in a debug
+// build. Rather than trust the host's thread to have room for MAX_CALL_DEPTH of them, every
+// STACK_CHECK_INTERVAL calls the interpreter looks at how much stack is left, and carries on
+// on a new segment of STACK_SEGMENT bytes if it's less than the calls up to the next check need.
+const STACK_CHECK_INTERVAL: usize = 16;
+const STACK_RED_ZONE: usize = 1024 * 1024;
+const STACK_SEGMENT: usize = 8 * 1024 * 1024;
+
+// Runs `f` on a new stack segment if the current one has less than STACK_RED_ZONE left.
+// The VM only recurses on the native stack when a native calls back into Lox code.
+pub fn ensure_stack<T>(f: impl FnOnce() -> T) -> T {
+    stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT, f)
+}
+
+// The standard library, which every program starts with in its globals. Modules start
+// without it and fall back on it instead, so their namespaces only hold what they define.
+pub fn standard_globals() -> Globals {
+    let globals = standard_library()
+        .into_iter()
+        .map(|native| (native.name(), Value::Callable(Rc::new(native))))
+        .collect();
+    Rc::new(RefCell::new(globals))
+}
+
 pub struct Interpreter {
     pub globals: EnvCell,
+    // Looked up when a global isn't defined in the module running
+    builtins: Globals,
     pub environment: EnvCell,
-    pub locals: HashMap<Rc<str>, usize>,
-    pub output: Vec<String>,
+    pub locals: HashMap<ExprId, (usize, usize)>,
+    // Where `print` statements write to, stdout unless the host says otherwise
+    output: OutputCell,
+    modules: ModulesCell,
+    // Where the error currently unwinding the interpreter was raised
+    error_span: Option<Span>,
+    // Calls currently running, outermost first
+    call_stack: Vec<StackFrame>,
+    // The call stack as it was when the unwinding error was raised, innermost first
+    backtrace: Vec<StackFrame>,
+    // Where the native that's running was called from, which its callbacks are traced to
+    native_call_site: Option<Span>,
 }
 
 impl Interpreter {
     pub fn new() -> Interpreter {
-        let globals = Rc::new(RefCell::new(Environment::new(None)));
-        // Basic clock to add to globals
-        // globals
-        //     .borrow_mut()
-        //     .define(Rc::clone(&Clock.name()), Value::Callable(Rc::new(Clock)));
+        let globals = Environment::with_globals(standard_globals()).into_cell();
         let environment = Rc::clone(&globals);
         Interpreter {
             globals,
+            builtins: standard_globals(),
             environment,
             locals: HashMap::new(),
-            output: Vec::new(),
+            output: Rc::new(RefCell::new(Writer(io::stdout()))),
+            modules: Rc::default(),
+            error_span: None,
+            call_stack: Vec::new(),
+            backtrace: Vec::new(),
+            native_call_site: None,
         }
     }
 
+    // Defines a callable in the global scope under its own name
+    pub fn register(&mut self, callable: Rc<dyn LoxCallable>) {
+        self.globals
+            .borrow_mut()
+            .define(callable.name(), Value::Callable(callable));
+    }
+
+    // Exposes a Rust closure to Lox code as a global function, e.g.
+    // interpreter.register_native("double", 1, |_, args| { ... });
+    pub fn register_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&mut dyn NativeContext, Vec<Value>) -> Result<Value, RuntimeError> + 'static,
+    {
+        self.register(Rc::new(NativeFunction::new(name, arity, function)));
+    }
+
+    pub fn set_output(&mut self, output: OutputCell) {
+        self.output = output;
+    }
+
+    pub fn set_modules(&mut self, modules: ModulesCell) {
+        self.modules = modules;
+    }
+
     pub fn get_environment(&self) -> String {
         let env_string = format!("Environment: {}", self.environment.borrow());
-        let envs = vec![env_string];
+        let envs = [env_string];
 
         envs.join("\n")
     }
 
-    pub fn interpret(&mut self, statements: Vec<Stmt>) -> Vec<String> {
+    // Runs until the first uncaught error; whatever was printed before it stays in `output`.
+    // A top-level `return` ends the script early, and its value is the script's result.
+    pub fn interpret(&mut self, statements: Vec<Stmt>) -> Result<Value, Diagnostic> {
+        self.error_span = None;
+        self.backtrace.clear();
         for statement in statements {
             match self.execute(&statement) {
-                Ok(_) => {}
-                Err(e) => {
-                    let msg = format!("[Runtime error] {}", e);
-                    self.add_print_result(msg);
-                    break;
-                }
-            };
+                Ok(()) => {}
+                Err(RuntimeError::Return(value)) => return Ok(value),
+                Err(e) => return Err(self.abort(e)),
+            }
         }
-        self.output.clone()
+        Ok(Value::Nil)
+    }
+
+    // Calls a function or class on behalf of the host application
+    pub fn call(&mut self, callee: Value, args: Vec<Value>) -> Result<Value, Diagnostic> {
+        self.error_span = None;
+        self.backtrace.clear();
+        self.call_value(callee, args, None)
+            .map_err(|e| self.abort(e))
+    }
+
+    // Reports an error that made it all the way out, where and how deep it was raised
+    fn abort(&mut self, error: RuntimeError) -> Diagnostic {
+        let diagnostic =
+            Diagnostic::error(Phase::Runtime, error.to_string(), self.error_span.take());
+        diagnostic.with_backtrace(std::mem::take(&mut self.backtrace))
     }
 
     // Since the original requires passing around Java Objects, we will continue
@@ -59,83 +147,37 @@ impl Interpreter {
     // to guarantee consistent types. The output will be a value of some type:
     // String(Box<String>), Number(f64), Boolean(bool), or Nil.
     pub fn evaluate(&mut self, expr: &Expr) -> Result<Value, RuntimeError> {
-        let result = match expr {
+        let result = self.evaluate_kind(&expr.kind, expr.span);
+        self.locate(result, expr.span)
+    }
+
+    fn evaluate_kind(&mut self, kind: &ExprKind, span: Span) -> Result<Value, RuntimeError> {
+        let result = match kind {
             // Unwrap a literal and return a copy of its value
             Literal(val) => val.clone(),
             Unary { op, rh_expr } => {
                 // Recursively evalute rh_expr if necessary
-                let right = &self.evaluate(rh_expr)?;
-
-                match op {
-                    // Extracting the number and negating it leaves us with f64, need to re-wrap it
-                    UnOperator::Minus => Value::Number(-extract_number(right)?),
-                    // Get the truth value of the rh_expr and return the negation
-                    UnOperator::Bang => Value::Boolean(!(is_truthy(right))),
-                }
+                let right = self.evaluate(rh_expr)?;
+                unary(op, &right)?
             }
             Binary {
                 lh_expr,
                 op,
                 rh_expr,
             } => {
-                let left = &self.evaluate(lh_expr)?;
-                let right = &self.evaluate(rh_expr)?;
-                match op {
-                    BiOperator::Minus => {
-                        Value::Number(extract_number(left)? - extract_number(right)?)
-                    }
-                    BiOperator::Slash => {
-                        Value::Number(extract_number(left)? / extract_number(right)?)
-                    }
-                    BiOperator::Star => {
-                        Value::Number(extract_number(left)? * extract_number(right)?)
-                    }
-                    BiOperator::Plus => match (left, right) {
-                        (Value::Number(left), Value::Number(right)) => Value::Number(left + right),
-                        (Value::String(left), Value::String(right)) => {
-                            Value::String(Box::new(format!("{}{}", &left, &right)))
-                        }
-                        (Value::Number(_), right) => {
-                            return Err(RuntimeError::type_error(right, "Expected number"))
-                        }
-                        (Value::String(_), right) => {
-                            return Err(RuntimeError::type_error(right, "Expected string"))
-                        }
-                        (left, _) => {
-                            return Err(RuntimeError::type_error(left, "Expected number or string"))
-                        }
-                    },
-                    BiOperator::Greater => {
-                        Value::Boolean(extract_number(left)? > extract_number(right)?)
-                    }
-                    BiOperator::GreaterEqual => {
-                        Value::Boolean(extract_number(left)? >= extract_number(right)?)
-                    }
-                    BiOperator::Less => {
-                        Value::Boolean(extract_number(left)? < extract_number(right)?)
-                    }
-                    BiOperator::LessEqual => {
-                        Value::Boolean(extract_number(left)? <= extract_number(right)?)
-                    }
-                    BiOperator::Eq => Value::Boolean(left == right),
-                    BiOperator::NotEq => Value::Boolean(left != right),
-                }
-            }
-            Variable(name) => {
-                self.environment.borrow().get(name)?
-                // self.look_up_var(name.to_owned())?
+                let left = self.evaluate(lh_expr)?;
+                let right = self.evaluate(rh_expr)?;
+                binary(op, &left, &right)?
             }
-
-            Assign { name, value } => {
+            Variable { id, name } => self.look_up_var(*id, name)?,
+            Assign { id, name, value } => {
                 let value = self.evaluate(value)?;
-                if let Some(distance) = self.locals.get(name) {
-                    self.environment.borrow_mut().assign_at(
-                        distance,
-                        Rc::clone(name),
-                        value.clone(),
-                    )?;
-                } else {
+                if let Some((distance, slot)) = self.locals.get(id) {
                     self.environment
+                        .borrow_mut()
+                        .assign_at(distance, *slot, value.clone());
+                } else {
+                    self.globals
                         .borrow_mut()
                         .assign(Rc::clone(name), value.clone())?;
                 }
@@ -153,7 +195,6 @@ impl Interpreter {
                     _ => self.evaluate(rh_expr)?,
                 }
             }
-            // TODO: include paren in parameters to pass line number to error
             Call {
                 callee, arguments, ..
             } => {
@@ -162,47 +203,139 @@ impl Interpreter {
                 for arg_expr in arguments {
                     args.push(self.evaluate(arg_expr)?);
                 }
-                if let Some(function) = callee.into_callable() {
-                    let arity = function.arity();
-                    if arguments.len() != arity {
-                        return Err(RuntimeError::ArityError {
-                            expected: arity,
-                            got: arguments.len(),
-                        });
+                self.call_value(callee, args, Some(span))?
+            }
+            Get { object, name } => match self.evaluate(object)? {
+                Value::Instance(instance) => instance.get(name)?,
+                object => {
+                    return Err(RuntimeError::type_error(
+                        &object,
+                        "Only instances have properties.",
+                    ))
+                }
+            },
+            Set {
+                object,
+                name,
+                value,
+            } => match self.evaluate(object)? {
+                Value::Instance(instance) => {
+                    let value = self.evaluate(value)?;
+                    instance.set(Rc::clone(name), value.clone());
+                    value
+                }
+                object => {
+                    return Err(RuntimeError::type_error(
+                        &object,
+                        "Only instances have fields.",
+                    ))
+                }
+            },
+            This { id } => self.look_up_var(*id, "this")?,
+            List(elements) => {
+                let mut values = Vec::with_capacity(elements.len());
+                for element in elements {
+                    values.push(self.evaluate(element)?);
+                }
+                Value::List(Rc::new(RefCell::new(values)))
+            }
+            Map(entries) => {
+                let mut map = HashMap::with_capacity(entries.len());
+                for (key, value) in entries {
+                    let key = MapKey::from_value(&self.evaluate(key)?)?;
+                    map.insert(key, self.evaluate(value)?);
+                }
+                Value::Map(Rc::new(RefCell::new(map)))
+            }
+            Index { object, index } => {
+                let object = self.evaluate(object)?;
+                let index = self.evaluate(index)?;
+                get_index(&object, &index)?
+            }
+            SetIndex {
+                object,
+                index,
+                value,
+            } => {
+                let object = self.evaluate(object)?;
+                let index = self.evaluate(index)?;
+                let value = self.evaluate(value)?;
+                set_index(&object, &index, value.clone())?;
+                value
+            }
+            Super { id, method } => {
+                // A `super` the resolver didn't bind, e.g. outside of a subclass method,
+                // is undefined, like on the VM
+                let undefined = || RuntimeError::UndefinedError {
+                    name: String::from("super"),
+                };
+                let distance = match self.locals.get(id) {
+                    Some(&(distance, _)) if distance > 0 => distance,
+                    _ => return Err(undefined()),
+                };
+                let superclass = match self.environment.borrow().get_at(&distance, 0, "super")? {
+                    Value::Class(superclass) => superclass,
+                    _ => return Err(undefined()),
+                };
+                // `this` is always bound in the scope just inside the one holding `super`
+                let instance = match self
+                    .environment
+                    .borrow()
+                    .get_at(&(distance - 1), 0, "this")?
+                {
+                    Value::Instance(instance) => instance,
+                    _ => return Err(undefined()),
+                };
+                match superclass.find_method(method) {
+                    Some(method) => Value::Callable(method.bind(instance)),
+                    None => {
+                        return Err(RuntimeError::PropertyError {
+                            name: method.to_string(),
+                        })
                     }
-                    function.call(self, args)?
-                } else {
-                    return Err(RuntimeError::CallableError {
-                        msg: "Can only call functions and classes",
-                    });
                 }
             }
+            Lambda { parameters, body } => {
+                let function = LoxFunction::new(
+                    Rc::from(LAMBDA_NAME),
+                    parameters.clone(),
+                    Rc::clone(body),
+                    Rc::clone(&self.environment),
+                    false,
+                );
+                Value::Callable(Rc::new(function))
+            }
         };
         Ok(result)
     }
 
     fn execute(&mut self, stmt: &Stmt) -> Result<(), RuntimeError> {
-        match stmt {
+        let result = self.execute_kind(&stmt.kind, stmt.span);
+        self.locate(result, stmt.span)
+    }
+
+    fn execute_kind(&mut self, kind: &StmtKind, span: Span) -> Result<(), RuntimeError> {
+        match kind {
             // TODO: For Print need to handle errors, since cannot use Display for Result
-            Stmt::Print(expr) => {
+            StmtKind::Print(expr) => {
                 let msg = format!("{}", self.evaluate(expr)?);
                 self.add_print_result(msg);
             }
-            Stmt::Expression(expr) => {
+            StmtKind::Expression(expr) => {
                 self.evaluate(expr)?;
             }
-            Stmt::Var { name, initializer } => {
+            StmtKind::Var { name, initializer } => {
                 let value = match initializer {
                     Some(expr) => self.evaluate(expr)?,
                     None => Value::Nil,
                 };
                 self.environment.borrow_mut().define(name.to_owned(), value);
             }
-            Stmt::Block(statements) => {
+            StmtKind::Block(statements) => {
                 let new_env = Environment::new(Some(Rc::clone(&self.environment))).into_cell();
                 self.execute_block(statements, new_env)?
             }
-            Stmt::If {
+            StmtKind::If {
                 condition,
                 then_branch,
                 else_branch,
@@ -213,12 +346,25 @@ impl Interpreter {
                     self.execute(else_branch)?;
                 }
             }
-            Stmt::While { condition, body } => {
+            StmtKind::While {
+                condition,
+                body,
+                increment,
+            } => {
                 while is_truthy(&self.evaluate(condition)?) {
-                    self.execute(body)?;
+                    match self.execute(body) {
+                        Err(RuntimeError::Break) => break,
+                        Ok(()) | Err(RuntimeError::Continue) => {}
+                        Err(e) => return Err(e),
+                    }
+                    if let Some(increment) = increment {
+                        self.evaluate(increment)?;
+                    }
                 }
             }
-            Stmt::Function {
+            StmtKind::Break => return Err(RuntimeError::Break),
+            StmtKind::Continue => return Err(RuntimeError::Continue),
+            StmtKind::Function {
                 name,
                 parameters,
                 body,
@@ -226,28 +372,118 @@ impl Interpreter {
                 let function = LoxFunction::new(
                     name.clone(),
                     parameters.clone(),
-                    body.clone(),
+                    Rc::clone(body),
                     Rc::clone(&self.environment),
+                    false,
                 );
                 self.environment
                     .borrow_mut()
                     .define(Rc::clone(name), Value::Callable(Rc::new(function)));
             }
-            Stmt::Return(expr) => {
+            StmtKind::Return(expr) => {
                 let val = self.evaluate(expr)?;
 
                 return Err(RuntimeError::Return(val));
             }
-            // Stmt::Class { name, methods } => {
-            //     self.environment
-            //         .borrow_mut()
-            //         .define(Rc::clone(&name), Value::Nil);
-            //     let class = LoxClass::new(Rc::clone(&name));
-            //     self.environment
-            //         .borrow_mut()
-            //         .assign(Rc::clone(&name), Value::Callable(Rc::new(class)));
-            // }
-            _ => unreachable!(),
+            StmtKind::Throw(expr) => return Err(RuntimeError::Thrown(self.evaluate(expr)?)),
+            StmtKind::Import { name, path } => {
+                let namespace = self.import(path, span)?;
+                self.environment
+                    .borrow_mut()
+                    .define(Rc::clone(name), namespace);
+            }
+            StmtKind::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                let new_env = Environment::new(Some(Rc::clone(&self.environment))).into_cell();
+                let mut result = self.execute_block(body, new_env);
+
+                if let Some((name, handler)) = catch {
+                    result = match result {
+                        Err(error) if error.is_catchable() => {
+                            // The error is handled, so the next one gets located afresh
+                            self.error_span = None;
+                            self.backtrace.clear();
+                            let mut env = Environment::new(Some(Rc::clone(&self.environment)));
+                            env.define(Rc::clone(name), error.into_value());
+                            self.execute_block(handler, env.into_cell())
+                        }
+                        result => result,
+                    };
+                }
+
+                if let Some(finally) = finally {
+                    // An error still unwinding keeps the location it was raised at,
+                    // unless the finally clause raises one of its own
+                    let error_span = self.error_span.take();
+                    let backtrace = std::mem::take(&mut self.backtrace);
+                    let new_env = Environment::new(Some(Rc::clone(&self.environment))).into_cell();
+                    match self.execute_block(finally, new_env) {
+                        Ok(()) => {
+                            self.error_span = error_span;
+                            self.backtrace = backtrace;
+                        }
+                        Err(error) => result = Err(error),
+                    }
+                }
+
+                result?
+            }
+            StmtKind::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                let superclass = match superclass {
+                    Some(expr) => match self.evaluate(expr)? {
+                        Value::Class(superclass) => Some(superclass),
+                        val => {
+                            return Err(RuntimeError::type_error(
+                                &val,
+                                "Superclass must be a class.",
+                            ))
+                        }
+                    },
+                    None => None,
+                };
+
+                // Methods of a subclass close over an extra scope holding `super`
+                let prev_env = Rc::clone(&self.environment);
+                if let Some(ref superclass) = superclass {
+                    let mut super_env = Environment::new(Some(Rc::clone(&self.environment)));
+                    super_env.define(Rc::from("super"), Value::Class(Rc::clone(superclass)));
+                    self.environment = super_env.into_cell();
+                }
+
+                let mut class_methods: HashMap<Rc<str>, Rc<dyn LoxMethod>> = HashMap::new();
+                for method in methods {
+                    if let StmtKind::Function {
+                        name,
+                        parameters,
+                        body,
+                    } = &method.kind
+                    {
+                        let function = LoxFunction::new(
+                            Rc::clone(name),
+                            parameters.clone(),
+                            Rc::clone(body),
+                            Rc::clone(&self.environment),
+                            &**name == "init",
+                        );
+                        class_methods.insert(Rc::clone(name), Rc::new(function));
+                    }
+                }
+
+                self.environment = prev_env;
+
+                // Methods only look the class up once they're called, so it can be defined last
+                let class = LoxClass::new(Rc::clone(name), superclass, class_methods);
+                self.environment
+                    .borrow_mut()
+                    .define(Rc::clone(name), Value::Class(Rc::new(class)));
+            }
         }
         Ok(())
     }
@@ -274,25 +510,256 @@ impl Interpreter {
         result
     }
 
-    pub fn resolve(&mut self, name: Rc<str>, depth: usize) {
-        self.locals.insert(name, depth);
+    // Runs the module at `path` the first time it's imported, and hands back its namespace
+    fn import(&mut self, path: &str, span: Span) -> Result<Value, RuntimeError> {
+        let modules = Rc::clone(&self.modules);
+        let import = modules.borrow_mut().begin(path)?;
+        let (name, source) = match import {
+            Import::Cached(namespace) => return Ok(namespace),
+            Import::Load { name, source } => (name, source),
+        };
+        let result = self.run_module(path, source, span);
+        modules
+            .borrow_mut()
+            .finish(&name, result.as_ref().ok().cloned());
+        result
+    }
+
+    // A module runs in a global scope of its own, with a frame of its own in backtraces
+    fn run_module(
+        &mut self,
+        path: &str,
+        source: String,
+        span: Span,
+    ) -> Result<Value, RuntimeError> {
+        let statements =
+            modules::parse(source, self).map_err(|d| import_error(path, d.to_string()))?;
+
+        let globals = Globals::default();
+        let environment = Environment::with_globals(Rc::clone(&globals)).into_cell();
+        let prev_globals = mem::replace(&mut self.globals, Rc::clone(&environment));
+        let prev_env = mem::replace(&mut self.environment, environment);
+        let result = self.with_frame(Rc::from(path), Some(span), |interpreter| {
+            for stmt in &statements {
+                match interpreter.execute(stmt) {
+                    // A `return` at the top of a module ends it early
+                    Err(RuntimeError::Return(_)) => break,
+                    result => result?,
+                }
+            }
+            Ok(())
+        });
+        self.globals = prev_globals;
+        self.environment = prev_env;
+
+        result?;
+        Ok(modules::namespace(globals))
+    }
+
+    // Remembers the innermost node an error came out of, so it can be reported with a location.
+    // The control flow used by `return`, `break` and `continue` isn't an error here.
+    fn locate<T>(
+        &mut self,
+        result: Result<T, RuntimeError>,
+        span: Span,
+    ) -> Result<T, RuntimeError> {
+        match result {
+            Err(RuntimeError::Return(_))
+            | Err(RuntimeError::Break)
+            | Err(RuntimeError::Continue) => {}
+            Err(_) if self.error_span.is_none() => {
+                self.error_span = Some(span);
+                self.backtrace = self.call_stack.iter().rev().cloned().collect();
+            }
+            _ => {}
+        }
+        result
+    }
+
+    fn call_value(
+        &mut self,
+        callee: Value,
+        args: Vec<Value>,
+        call_site: Option<Span>,
+    ) -> Result<Value, RuntimeError> {
+        match callee {
+            // Natives get no frame, there's no Lox code inside them to trace
+            Value::Callable(function) if function.as_any().is::<NativeFunction>() => {
+                check_arity(function.arity(), args.len())?;
+                let call_site = mem::replace(&mut self.native_call_site, call_site);
+                let result = function.call(self, args);
+                self.native_call_site = call_site;
+                // Where a callback's error came from is only wanted if the native let it through
+                if result.is_ok() {
+                    self.error_span = None;
+                    self.backtrace.clear();
+                }
+                result
+            }
+            Value::Callable(function) => {
+                check_arity(function.arity(), args.len())?;
+                self.with_frame(function.name(), call_site, |interpreter| {
+                    function.call(interpreter, args)
+                })
+            }
+            Value::Class(class) => {
+                check_arity(class.arity(), args.len())?;
+                match class.find_method("init") {
+                    Some(initializer) => {
+                        self.with_frame(initializer.name(), call_site, |interpreter| {
+                            class.call(interpreter, args)
+                        })
+                    }
+                    None => class.call(self, args),
+                }
+            }
+            _ => Err(RuntimeError::CallableError {
+                msg: "Can only call functions and classes",
+            }),
+        }
+    }
+
+    // Runs a call of `function` from `call_site` with a frame pushed for it on the call stack
+    fn with_frame<T>(
+        &mut self,
+        function: Rc<str>,
+        call_site: Option<Span>,
+        call: impl FnOnce(&mut Self) -> Result<T, RuntimeError>,
+    ) -> Result<T, RuntimeError> {
+        if self.call_stack.len() >= MAX_CALL_DEPTH {
+            return Err(RuntimeError::StackOverflow);
+        }
+        self.call_stack.push(StackFrame {
+            function: function.to_string(),
+            call_site,
+        });
+        let result = if self.call_stack.len().is_multiple_of(STACK_CHECK_INTERVAL) {
+            ensure_stack(|| call(self))
+        } else {
+            call(self)
+        };
+        self.call_stack.pop();
+        result
+    }
+
+    pub fn resolve(&mut self, id: ExprId, depth: usize, slot: usize) {
+        self.locals.insert(id, (depth, slot));
     }
 
-    // fn look_up_var(&mut self, name: Rc<str>) -> Result<Value, RuntimeError> {
-    //     let distance = self.locals.get(&name);
-    //     let val = match distance {
-    //         Some(depth) => self.environment.borrow().get_at(distance.unwrap(), &name),
-    //         None => self.globals.borrow().get(&name),
-    //     };
-    //     val
-    // }
+    // Resolved locals are fetched from the exact enclosing scope the resolver found them in,
+    // anything else must be a global
+    fn look_up_var(&self, id: ExprId, name: &str) -> Result<Value, RuntimeError> {
+        match self.locals.get(&id) {
+            Some((distance, slot)) => self.environment.borrow().get_at(distance, *slot, name),
+            None => self
+                .globals
+                .borrow()
+                .get(name)
+                .or_else(|error| self.builtins.borrow().get(name).cloned().ok_or(error)),
+        }
+    }
 
     fn add_print_result(&mut self, msg: String) {
-        self.output.push(msg);
+        self.output.borrow_mut().print(&msg);
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NativeContext for Interpreter {
+    fn call(&mut self, callee: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        self.call_value(callee, args, self.native_call_site)
+    }
+}
+
+// The operators are shared with the bytecode VM, so that both backends agree on
+// results and on which RuntimeError is raised for bad operands
+pub fn unary(op: &UnOperator, right: &Value) -> Result<Value, RuntimeError> {
+    match op {
+        // Extracting the number and negating it leaves us with f64, need to re-wrap it
+        UnOperator::Minus => Ok(Value::Number(-extract_number(right)?)),
+        // Get the truth value of the rh_expr and return the negation
+        UnOperator::Bang => Ok(Value::Boolean(!(is_truthy(right)))),
+        // Strings are interpolated as they are, anything else as it would be printed
+        UnOperator::Stringify => Ok(match right {
+            Value::String(s) => Value::String(s.clone()),
+            value => Value::String(Box::new(value.to_string())),
+        }),
+    }
+}
+
+pub fn binary(op: &BiOperator, left: &Value, right: &Value) -> Result<Value, RuntimeError> {
+    let result = match op {
+        BiOperator::Minus => Value::Number(extract_number(left)? - extract_number(right)?),
+        BiOperator::Slash => Value::Number(extract_number(left)? / extract_number(right)?),
+        BiOperator::Star => Value::Number(extract_number(left)? * extract_number(right)?),
+        BiOperator::Plus => match (left, right) {
+            (Value::Number(left), Value::Number(right)) => Value::Number(left + right),
+            (Value::String(left), Value::String(right)) => {
+                Value::String(Box::new(format!("{}{}", &left, &right)))
+            }
+            (Value::Number(_), right) => {
+                return Err(RuntimeError::type_error(right, "Expected number"))
+            }
+            (Value::String(_), right) => {
+                return Err(RuntimeError::type_error(right, "Expected string"))
+            }
+            (left, _) => return Err(RuntimeError::type_error(left, "Expected number or string")),
+        },
+        BiOperator::Greater => Value::Boolean(extract_number(left)? > extract_number(right)?),
+        BiOperator::GreaterEqual => Value::Boolean(extract_number(left)? >= extract_number(right)?),
+        BiOperator::Less => Value::Boolean(extract_number(left)? < extract_number(right)?),
+        BiOperator::LessEqual => Value::Boolean(extract_number(left)? <= extract_number(right)?),
+        BiOperator::Eq => Value::Boolean(left == right),
+        BiOperator::NotEq => Value::Boolean(left != right),
+    };
+    Ok(result)
+}
+
+pub fn get_index(object: &Value, index: &Value) -> Result<Value, RuntimeError> {
+    match object {
+        Value::List(list) => {
+            let list = list.borrow();
+            Ok(list[list_index(index, list.len())?].clone())
+        }
+        Value::Map(map) => match map.borrow().get(&MapKey::from_value(index)?) {
+            Some(value) => Ok(value.clone()),
+            None => Err(RuntimeError::KeyError {
+                key: index.to_string(),
+            }),
+        },
+        object => Err(RuntimeError::type_error(
+            object,
+            "Only lists and maps can be indexed.",
+        )),
+    }
+}
+
+pub fn set_index(object: &Value, index: &Value, value: Value) -> Result<(), RuntimeError> {
+    match object {
+        Value::List(list) => {
+            let mut list = list.borrow_mut();
+            let index = list_index(index, list.len())?;
+            list[index] = value;
+        }
+        Value::Map(map) => {
+            map.borrow_mut().insert(MapKey::from_value(index)?, value);
+        }
+        object => {
+            return Err(RuntimeError::type_error(
+                object,
+                "Only lists and maps can be indexed.",
+            ))
+        }
     }
+    Ok(())
 }
 
-fn is_truthy(val: &Value) -> bool {
+pub fn is_truthy(val: &Value) -> bool {
     !matches!(val, Value::Boolean(false) | Value::Nil)
 }
 
@@ -305,22 +772,89 @@ fn extract_number(val: &Value) -> Result<f64, RuntimeError> {
     }
 }
 
+// Checks that a Value is a whole number within the bounds of a list of length `len`
+fn list_index(index: &Value, len: usize) -> Result<usize, RuntimeError> {
+    let index = match index {
+        Value::Number(num) if num.fract() == 0.0 => *num,
+        _ => {
+            return Err(RuntimeError::type_error(
+                index,
+                "List index must be an integer.",
+            ))
+        }
+    };
+    if index < 0.0 || index >= len as f64 {
+        return Err(RuntimeError::IndexError { index, len });
+    }
+    Ok(index as usize)
+}
+
+pub fn check_arity(expected: usize, got: usize) -> Result<(), RuntimeError> {
+    if expected != got {
+        return Err(RuntimeError::ArityError { expected, got });
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone)]
 pub enum RuntimeError {
     TypeError { val: String, msg: &'static str },
     UndefinedError { name: String },
     CallableError { msg: &'static str },
     ArityError { expected: usize, got: usize },
+    PropertyError { name: String },
+    IndexError { index: f64, len: usize },
+    KeyError { key: String },
+    StackOverflow,
+    ImportError { path: String, msg: String },
+    // Raised by a `throw` statement
+    Thrown(Value),
     Return(Value),
+    Break,
+    Continue,
 }
 
 impl RuntimeError {
-    fn type_error(val: &Value, msg: &'static str) -> RuntimeError {
+    pub fn type_error(val: &Value, msg: &'static str) -> RuntimeError {
         RuntimeError::TypeError {
             val: val.to_string(),
             msg,
         }
     }
+
+    // `return`, `break` and `continue` unwind the interpreter too, but a `try` lets them through
+    pub fn is_catchable(&self) -> bool {
+        !matches!(
+            self,
+            RuntimeError::Return(_) | RuntimeError::Break | RuntimeError::Continue
+        )
+    }
+
+    // What a `catch` clause receives: the thrown value itself, or for an error raised by the
+    // interpreter, an Error instance with the error's `kind` and `message`
+    pub fn into_value(self) -> Value {
+        let kind = match self {
+            RuntimeError::Thrown(value) => return value,
+            RuntimeError::TypeError { .. } => "TypeError",
+            RuntimeError::UndefinedError { .. } => "UndefinedError",
+            RuntimeError::CallableError { .. } => "CallableError",
+            RuntimeError::ArityError { .. } => "ArityError",
+            RuntimeError::PropertyError { .. } => "PropertyError",
+            RuntimeError::IndexError { .. } => "IndexError",
+            RuntimeError::KeyError { .. } => "KeyError",
+            RuntimeError::StackOverflow => "StackOverflow",
+            RuntimeError::ImportError { .. } => "ImportError",
+            RuntimeError::Return(_) | RuntimeError::Break | RuntimeError::Continue => {
+                unreachable!()
+            }
+        };
+
+        let class = LoxClass::new(Rc::from(ERROR_CLASS), None, HashMap::new());
+        let error = LoxInstance::new(Rc::new(class));
+        error.set(Rc::from("kind"), Value::from(kind));
+        error.set(Rc::from("message"), Value::from(self.to_string()));
+        Value::Instance(Rc::new(error))
+    }
 }
 
 impl fmt::Display for RuntimeError {
@@ -332,7 +866,26 @@ impl fmt::Display for RuntimeError {
             RuntimeError::ArityError { expected, got } => {
                 write!(f, "Expected {} arguments but got {}", expected, got)
             }
+            RuntimeError::PropertyError { name } => write!(f, "Undefined property '{}'", name),
+            RuntimeError::IndexError { index, len } => {
+                write!(f, "Index {} out of bounds for length {}", index, len)
+            }
+            RuntimeError::KeyError { key } => write!(f, "Undefined key {}", key),
+            RuntimeError::StackOverflow => write!(f, "Stack overflow"),
+            RuntimeError::ImportError { path, msg } => {
+                write!(f, "Can't import \"{}\": {}", path, msg)
+            }
+            // A caught error that is thrown again reads the same as it did the first time
+            RuntimeError::Thrown(Value::Instance(error)) if &*error.class_name() == ERROR_CLASS => {
+                match error.fields().get("message") {
+                    Some(Value::String(message)) => write!(f, "{}", message),
+                    _ => write!(f, "Uncaught {}", error),
+                }
+            }
+            RuntimeError::Thrown(value) => write!(f, "Uncaught {}", value),
             RuntimeError::Return { .. } => unreachable!(),
+            RuntimeError::Break => write!(f, "Can't use 'break' outside of a loop."),
+            RuntimeError::Continue => write!(f, "Can't use 'continue' outside of a loop."),
         }
     }
 }
@@ -1,68 +1,236 @@
 pub mod ast;
+pub mod chunk;
+pub mod compiler;
+pub mod convert;
+pub mod diagnostic;
 pub mod environment;
 pub mod function;
 pub mod interpreter;
+pub mod modules;
+pub mod output;
 pub mod parser;
 pub mod resolver;
 pub mod scanner;
+pub mod session;
 pub mod token;
+pub mod vm;
 
-use ast::Stmt;
+use ast::{Stmt, StmtKind, Value};
+use compiler::Compiler;
+use diagnostic::{Diagnostic, Phase, Span};
 use interpreter::Interpreter;
+use modules::{Loader, ModulesCell};
+use output::{Buffer, Output};
 use parser::Parser;
-use resolver::Resolver;
+use resolver::{Resolution, Resolver};
 use scanner::Scanner;
-use token::Token;
+use std::cell::RefCell;
+use std::rc::Rc;
+use token::{Token, TokenType};
+use vm::Vm;
+
+// Which engine executes the parse tree; both produce the same output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    TreeWalk,
+    Bytecode,
+}
 
 pub struct Rulox {
     pub had_errors: bool,
+    backend: Backend,
+    // Set for REPL sessions, which echo the value of a trailing bare expression
+    interactive: bool,
     source: String,
-    environments: String,
+    // Set by the scanner when the source ends inside a string or block comment
+    unterminated: bool,
     parse_tree: Vec<Stmt>,
     token_stream: Vec<Token>,
-    pub error_msg: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+    resolutions: Vec<Resolution>,
+    // Both engines live as long as the Rulox, so globals carry over from one run to the next
+    interpreter: Interpreter,
+    vm: Vm,
+    // Collects what the program prints, until the host asks for it to be sent elsewhere
+    buffer: Option<Rc<RefCell<Buffer>>>,
+    // Modules imported by the programs run so far, which either engine can reuse
+    modules: ModulesCell,
 }
 
 impl Rulox {
     pub fn new(source: String) -> Rulox {
+        let buffer = Rc::new(RefCell::new(Buffer::default()));
+        let mut interpreter = Interpreter::new();
+        interpreter.set_output(buffer.clone());
+        let mut vm = Vm::new();
+        vm.set_output(buffer.clone());
+        let modules = ModulesCell::default();
+        interpreter.set_modules(modules.clone());
+        vm.set_modules(modules.clone());
+
         Rulox {
             had_errors: false,
+            backend: Backend::TreeWalk,
+            interactive: false,
             source,
-            environments: String::new(),
+            unterminated: false,
             token_stream: Vec::new(),
             parse_tree: Vec::new(),
-            error_msg: Vec::new(),
+            diagnostics: Vec::new(),
+            resolutions: Vec::new(),
+            interpreter,
+            vm,
+            buffer: Some(buffer),
+            modules,
         }
     }
 
-    pub fn run(&mut self) -> Vec<String> {
-        let mut interpreter = Interpreter::new();
+    // An empty session for a REPL, fed one input at a time through `load`
+    pub fn interactive() -> Rulox {
+        let mut rulox = Rulox::new(String::new());
+        rulox.interactive = true;
+        rulox
+    }
+
+    // Replaces the source with the next input of the session. Everything left over from
+    // the previous one is dropped, apart from the state of the program it ran.
+    pub fn load(&mut self, source: String) {
+        self.source = source;
+        self.had_errors = false;
+        self.unterminated = false;
+        self.token_stream.clear();
+        self.parse_tree.clear();
+        self.diagnostics.clear();
+        self.resolutions.clear();
+    }
 
-        let mut resolver = Resolver::new(&mut interpreter);
+    // Binds every variable reference in the parse tree to the scope it refers to.
+    // Problems found here are errors, which stop `run` like syntax errors do.
+    pub fn resolve(&mut self) {
+        // The parse tree is incomplete after a syntax error
+        if self.had_errors {
+            return;
+        }
 
-        let mut output = Vec::new();
-        if let Err(e) = resolver.resolve_source(&self.parse_tree) {
-            output.push(format!("Resolver error: {:?}", e))
+        let mut resolver = Resolver::new(&mut self.interpreter);
+        let diagnostics = resolver.resolve_source(&self.parse_tree);
+        self.resolutions = resolver.take_resolutions();
+        for diagnostic in diagnostics {
+            self.report(diagnostic);
         }
+    }
 
+    // Runs the resolved parse tree. What it printed is returned, unless it was already
+    // streamed to an output set with `set_output`.
+    pub fn run(&mut self) -> Vec<String> {
         if self.had_errors {
-            output.append(&mut self.error_msg);
-            return output;
+            return Vec::new();
+        }
+
+        if let Err(diagnostic) = self.execute() {
+            self.report(diagnostic);
         }
 
-        let mut result = interpreter.interpret(self.parse_tree.clone());
-        output.append(&mut result);
-        self.environments = interpreter.get_environment();
+        let buffer = match &self.buffer {
+            Some(buffer) => buffer,
+            None => return Vec::new(),
+        };
+        let mut output = buffer.borrow_mut().take();
 
-        if output.is_empty() {
+        // A REPL prints nothing after a definition, the prompt coming back is enough
+        if output.is_empty() && self.diagnostics.is_empty() && !self.interactive {
             output.push(String::from("No output to display."));
         }
 
         output
     }
 
+    // Runs the parse tree on the selected backend, returning the value of a top-level `return`
+    fn execute(&mut self) -> Result<Value, Diagnostic> {
+        match self.backend {
+            Backend::TreeWalk => self.interpreter.interpret(self.parse_tree.clone()),
+            Backend::Bytecode => {
+                let function = Compiler::new()
+                    .compile(&self.parse_tree)
+                    .map_err(|e| Diagnostic::error(Phase::Compile, e.msg, Some(e.span)))?;
+                self.vm.interpret(function)
+            }
+        }
+    }
+
+    // Sends everything the program prints to `output` as it happens, rather than
+    // collecting it to be returned by `run`
+    pub fn set_output(&mut self, output: impl Output + 'static) {
+        let output = Rc::new(RefCell::new(output));
+        self.interpreter.set_output(output.clone());
+        self.vm.set_output(output);
+        self.buffer = None;
+    }
+
+    // Decides where imported modules come from, files relative to the current directory
+    // by default. Modules imported so far stay cached.
+    pub fn set_loader(&mut self, loader: impl Loader + 'static) {
+        self.modules.borrow_mut().set_loader(loader);
+    }
+
+    // Everything reported by the scanner, parser, resolver and runtime so far, in order
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    // Whether the tokenized source stops partway through, inside a string, a block comment
+    // or an unclosed bracket, so a REPL should read more lines before running it
+    pub fn is_incomplete(&self) -> bool {
+        let depth = self
+            .token_stream
+            .iter()
+            .fold(0, |depth, token| match token.t_type {
+                TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => depth + 1,
+                TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => {
+                    depth - 1
+                }
+                _ => depth,
+            });
+        self.unterminated || depth > 0
+    }
+
+    // Every variable, function and class defined globally by the programs run so far
+    pub fn global_names(&self) -> Vec<Rc<str>> {
+        match self.backend {
+            Backend::TreeWalk => self.interpreter.globals.borrow().names(),
+            Backend::Bytecode => self.vm.global_names(),
+        }
+    }
+
     pub fn get_environment(&self) -> String {
-        self.environments.clone()
+        match self.backend {
+            Backend::TreeWalk => self.interpreter.get_environment(),
+            Backend::Bytecode => self.vm.get_environment(),
+        }
+    }
+
+    pub fn tokens(&self) -> &[Token] {
+        &self.token_stream
+    }
+
+    pub fn parse_tree(&self) -> &[Stmt] {
+        &self.parse_tree
+    }
+
+    pub fn resolutions(&self) -> &[Resolution] {
+        &self.resolutions
+    }
+
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    // Compiles the parse tree and renders the resulting chunks as text
+    pub fn disassemble(&self) -> String {
+        match Compiler::new().compile(&self.parse_tree) {
+            Ok(function) => chunk::disassemble(&function),
+            Err(e) => Diagnostic::error(Phase::Compile, e.msg, Some(e.span)).to_string(),
+        }
     }
 
     pub fn tokenize(&mut self) {
@@ -72,29 +240,26 @@ impl Rulox {
 
     pub fn parse(&mut self) {
         let mut parser = Parser::new(self.token_stream.clone(), self);
-        self.parse_tree = parser.parse()
-    }
+        self.parse_tree = parser.parse();
 
-    fn error_line(&mut self, line: usize, msg: String) {
-        self.report((line, 0), "".to_string(), msg);
+        // Typing `x + 1;` at the prompt shows the result, as if it were `print x + 1;`
+        if self.interactive {
+            if let Some(stmt) = self.parse_tree.last_mut() {
+                if let StmtKind::Expression(expr) = &stmt.kind {
+                    stmt.kind = StmtKind::Print(expr.clone());
+                }
+            }
+        }
     }
 
-    fn error_token(
-        &mut self,
-        token_type: String,
-        token_line: usize,
-        token_col: usize,
-        msg: String,
-    ) {
-        self.report((token_line, token_col), token_type, msg);
+    fn error(&mut self, phase: Phase, span: Span, msg: String) {
+        self.report(Diagnostic::error(phase, msg, Some(span)));
     }
 
-    fn report(&mut self, pos: (usize, usize), t_type: String, msg: String) {
-        let (line, col) = pos;
-        self.error_msg.push(format!(
-            "[error @ {} : {}] \n\t --> `{}` = {}",
-            line, col, t_type, msg
-        ));
-        self.had_errors = true;
+    fn report(&mut self, diagnostic: Diagnostic) {
+        if diagnostic.is_error() {
+            self.had_errors = true;
+        }
+        self.diagnostics.push(diagnostic);
     }
 }
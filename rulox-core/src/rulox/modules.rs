@@ -0,0 +1,179 @@
+// Lets a program `import` other Lox files as modules.
+//
+// Where a module's source comes from is up to a Loader: the CLI reads files from disk,
+// while the wasm build serves them from memory. Each module runs once, in a global scope
+// of its own, which the namespace it's imported as reads and writes.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use super::ast::{Stmt, Value};
+use super::diagnostic::Diagnostic;
+use super::environment::Globals;
+use super::function::{LoxClass, LoxInstance};
+use super::interpreter::{Interpreter, RuntimeError};
+use super::resolver::Resolver;
+use super::Rulox;
+
+// The class of the namespaces modules are imported as
+pub const MODULE_CLASS: &str = "module";
+
+pub trait Loader {
+    // Names the module an import path refers to. Modules are cached by name, so paths
+    // that lead to the same module should resolve to the same name.
+    fn resolve(&self, path: &str) -> io::Result<String> {
+        Ok(path.to_owned())
+    }
+
+    // Reads the source of a module, given its resolved name
+    fn load(&self, name: &str) -> io::Result<String>;
+}
+
+// Reads modules from files, with import paths relative to a root directory
+pub struct FileLoader {
+    root: PathBuf,
+}
+
+impl FileLoader {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl Loader for FileLoader {
+    fn resolve(&self, path: &str) -> io::Result<String> {
+        let path = self.root.join(path).canonicalize()?;
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    fn load(&self, name: &str) -> io::Result<String> {
+        fs::read_to_string(name)
+    }
+}
+
+// Serves the modules the host added up front, e.g. `loader.add("util.lox", source)`
+#[derive(Debug, Clone, Default)]
+pub struct MemoryLoader {
+    modules: HashMap<String, String>,
+}
+
+impl MemoryLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, path: &str, source: String) {
+        self.modules.insert(path.to_owned(), source);
+    }
+}
+
+impl Loader for MemoryLoader {
+    fn load(&self, name: &str) -> io::Result<String> {
+        self.modules
+            .get(name)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such module"))
+    }
+}
+
+// What `Modules::begin` found for an import
+pub enum Import {
+    // The module already ran, this is its namespace
+    Cached(Value),
+    // The module has to be run, then its namespace handed to `Modules::finish`
+    Load { name: String, source: String },
+}
+
+// Shared by the interpreter and the VM, so either backend imports through the same loader
+pub type ModulesCell = Rc<RefCell<Modules>>;
+
+pub struct Modules {
+    loader: Box<dyn Loader>,
+    // Namespaces of the modules imported so far, by name
+    cache: HashMap<String, Value>,
+    // Modules that are running right now, outermost first, to catch import cycles
+    loading: Vec<String>,
+}
+
+impl Modules {
+    pub fn new(loader: impl Loader + 'static) -> Self {
+        Self {
+            loader: Box::new(loader),
+            cache: HashMap::new(),
+            loading: Vec::new(),
+        }
+    }
+
+    pub fn set_loader(&mut self, loader: impl Loader + 'static) {
+        self.loader = Box::new(loader);
+    }
+
+    // Looks up the module `path` refers to, and starts loading it unless it already ran
+    pub fn begin(&mut self, path: &str) -> Result<Import, RuntimeError> {
+        let io_error = |e: io::Error| import_error(path, e.to_string());
+        let name = self.loader.resolve(path).map_err(io_error)?;
+        if let Some(namespace) = self.cache.get(&name) {
+            return Ok(Import::Cached(namespace.clone()));
+        }
+        if self.loading.contains(&name) {
+            return Err(import_error(path, String::from("Import cycle")));
+        }
+
+        let source = self.loader.load(&name).map_err(io_error)?;
+        self.loading.push(name.clone());
+        Ok(Import::Load { name, source })
+    }
+
+    // Caches the namespace of a module that ran to the end. A module that failed isn't,
+    // so importing it again runs it again.
+    pub fn finish(&mut self, name: &str, namespace: Option<Value>) {
+        self.loading.retain(|loading| loading != name);
+        if let Some(namespace) = namespace {
+            self.cache.insert(name.to_owned(), namespace);
+        }
+    }
+}
+
+impl Default for Modules {
+    // Imports are relative to the current directory
+    fn default() -> Self {
+        Self::new(FileLoader::new("."))
+    }
+}
+
+pub fn import_error(path: &str, msg: String) -> RuntimeError {
+    RuntimeError::ImportError {
+        path: path.to_owned(),
+        msg,
+    }
+}
+
+// Scans, parses and resolves the source of a module, resolving its variables into
+// `interpreter`. Only the first error is returned, since the import fails either way.
+pub fn parse(source: String, interpreter: &mut Interpreter) -> Result<Vec<Stmt>, Diagnostic> {
+    let mut rulox = Rulox::new(source);
+    rulox.tokenize();
+    rulox.parse();
+    if !rulox.had_errors {
+        let diagnostics = Resolver::new(interpreter).resolve_source(&rulox.parse_tree);
+        for diagnostic in diagnostics {
+            rulox.report(diagnostic);
+        }
+    }
+
+    match rulox.diagnostics.into_iter().find(|d| d.is_error()) {
+        Some(diagnostic) => Err(diagnostic),
+        None => Ok(rulox.parse_tree),
+    }
+}
+
+// A module's namespace reads and writes the globals it runs in, which don't include the
+// standard library
+pub fn namespace(globals: Globals) -> Value {
+    let class = LoxClass::new(Rc::from(MODULE_CLASS), None, HashMap::new());
+    Value::Instance(Rc::new(LoxInstance::with_fields(Rc::new(class), globals)))
+}
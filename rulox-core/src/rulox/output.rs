@@ -0,0 +1,54 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::mem;
+use std::rc::Rc;
+
+// Programs print through an Output as each `print` statement runs, so a host can show
+// their output as it happens instead of after the whole program has finished
+pub trait Output {
+    // Receives the text of one `print` statement, without a trailing newline
+    fn print(&mut self, line: &str);
+}
+
+// Shared between the interpreter and the VM, so that either backend prints to the same place
+pub type OutputCell = Rc<RefCell<dyn Output>>;
+
+// Keeps every printed line, for hosts that want the whole output at once
+#[derive(Debug, Default)]
+pub struct Buffer {
+    lines: Vec<String>,
+}
+
+impl Buffer {
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    // Empties the buffer, returning what was printed into it so far
+    pub fn take(&mut self) -> Vec<String> {
+        mem::take(&mut self.lines)
+    }
+}
+
+impl Output for Buffer {
+    fn print(&mut self, line: &str) {
+        self.lines.push(line.to_owned());
+    }
+}
+
+// Writes each line out as it's printed, e.g. `Writer(io::stdout())`.
+// Like `print` in most languages, a failed write (say, to a closed pipe) doesn't stop the program.
+pub struct Writer<W: Write>(pub W);
+
+impl<W: Write> Output for Writer<W> {
+    fn print(&mut self, line: &str) {
+        let _ = writeln!(self.0, "{}", line);
+    }
+}
+
+// Any closure taking a line can act as an Output, e.g. one that forwards it to a UI
+impl<F: FnMut(&str)> Output for F {
+    fn print(&mut self, line: &str) {
+        self(line)
+    }
+}
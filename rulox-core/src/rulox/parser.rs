@@ -6,9 +6,15 @@
 // term           → factor ( ( "-" | "+" ) factor )* ;
 // factor         → unary ( ( "/" | "*" ) unary )* ;
 // unary          → ( "!" | "-" ) unary
-//                | primary ;
-// primary        → NUMBER | STRING | "true" | "false" | "nil"
-//                | "(" expression ")" ;
+//                | call ;
+// call           → primary ( "(" arguments? ")" | "." IDENTIFIER | "[" expression "]" )* ;
+// primary        → NUMBER | STRING | "true" | "false" | "nil" | "this"
+//                | IDENTIFIER | "(" expression ")" | "super" "." IDENTIFIER
+//                | "[" ( expression ( "," expression )* )? "]"
+//                | "{" ( entry ( "," entry )* )? "}"
+//                | "fun" "(" parameters? ")" block
+//                | ( INTERPOLATION expression )+ STRING ;
+// entry          → expression ":" expression ;
 //
 // Translating to code:
 // Grammar notation 	  Code representation
@@ -23,9 +29,14 @@
 #![allow(dead_code)]
 #[allow(clippy::all)]
 use std::fmt;
+use std::path::Path;
 use std::rc::Rc;
 
-use super::ast::{BiOperator, Expr, LogicOperator, Stmt, UnOperator, Value};
+use super::ast::{
+    next_expr_id, BiOperator, Expr, ExprKind, LogicOperator, Stmt, StmtKind, UnOperator, Value,
+};
+use super::diagnostic::{Phase, Span};
+use super::scanner::KEYWORDS;
 use super::token::TokenType::*;
 use super::token::{Token, TokenType};
 use super::Rulox;
@@ -54,12 +65,13 @@ impl<'a> Parser<'a> {
         }
     }
 
+    // Syntax errors are reported through Rulox as they're found, and parsing carries on
+    // so that every one of them is reported in a single pass
     pub fn parse(&mut self) -> Vec<Stmt> {
         let mut statements = Vec::new();
         while !self.is_at_end() {
-            match self.declaration() {
-                Ok(stmt) => statements.push(stmt),
-                Err(_) => statements.push(Stmt::Return(Expr::Literal(Value::Nil))),
+            if let Some(stmt) = self.declaration() {
+                statements.push(stmt);
             }
         }
         statements
@@ -74,14 +86,27 @@ impl<'a> Parser<'a> {
 
         if self.check(vec![Equal]).is_some() {
             let value = self.assignment()?;
+            let span = expr.span.to(value.span);
 
-            match expr {
-                Expr::Variable(name) => Ok(Expr::Assign {
+            let kind = match expr.kind {
+                ExprKind::Variable { id, name } => ExprKind::Assign {
+                    id,
                     name,
                     value: Box::new(value),
-                }),
-                _ => Err(ParseError),
-            }
+                },
+                ExprKind::Get { object, name } => ExprKind::Set {
+                    object,
+                    name,
+                    value: Box::new(value),
+                },
+                ExprKind::Index { object, index } => ExprKind::SetIndex {
+                    object,
+                    index,
+                    value: Box::new(value),
+                },
+                _ => return Err(self.error(String::from("Invalid assignment target."))),
+            };
+            Ok(Expr::new(kind, span))
         } else {
             Ok(expr)
         }
@@ -92,11 +117,15 @@ impl<'a> Parser<'a> {
 
         while self.check(vec![Or]).is_some() {
             let right = self.and()?;
-            expr = Expr::Logical {
-                lh_expr: Box::new(expr),
-                op: LogicOperator::Or,
-                rh_expr: Box::new(right),
-            };
+            let span = expr.span.to(right.span);
+            expr = Expr::new(
+                ExprKind::Logical {
+                    lh_expr: Box::new(expr),
+                    op: LogicOperator::Or,
+                    rh_expr: Box::new(right),
+                },
+                span,
+            );
         }
 
         Ok(expr)
@@ -107,11 +136,15 @@ impl<'a> Parser<'a> {
 
         while self.check(vec![And]).is_some() {
             let right = self.equality()?;
-            expr = Expr::Logical {
-                lh_expr: Box::new(expr),
-                op: LogicOperator::And,
-                rh_expr: Box::new(right),
-            };
+            let span = expr.span.to(right.span);
+            expr = Expr::new(
+                ExprKind::Logical {
+                    lh_expr: Box::new(expr),
+                    op: LogicOperator::And,
+                    rh_expr: Box::new(right),
+                },
+                span,
+            );
         }
 
         Ok(expr)
@@ -127,11 +160,7 @@ impl<'a> Parser<'a> {
                 BiOperator::Eq
             };
             let right: Expr = self.comparison()?;
-            expr = Expr::Binary {
-                lh_expr: Box::new(expr),
-                op,
-                rh_expr: Box::new(right),
-            };
+            expr = binary(expr, op, right);
         }
 
         Ok(expr)
@@ -149,11 +178,7 @@ impl<'a> Parser<'a> {
                 _ => unreachable!(),
             };
             let right: Expr = self.term()?;
-            expr = Expr::Binary {
-                lh_expr: Box::new(expr),
-                op,
-                rh_expr: Box::new(right),
-            };
+            expr = binary(expr, op, right);
         }
 
         Ok(expr)
@@ -169,11 +194,7 @@ impl<'a> Parser<'a> {
                 BiOperator::Plus
             };
             let right: Expr = self.factor()?;
-            expr = Expr::Binary {
-                lh_expr: Box::new(expr),
-                op,
-                rh_expr: Box::new(right),
-            };
+            expr = binary(expr, op, right);
         }
 
         Ok(expr)
@@ -189,11 +210,7 @@ impl<'a> Parser<'a> {
                 BiOperator::Star
             };
             let right: Expr = self.unary()?;
-            expr = Expr::Binary {
-                lh_expr: Box::new(expr),
-                op,
-                rh_expr: Box::new(right),
-            };
+            expr = binary(expr, op, right);
         }
 
         Ok(expr)
@@ -201,16 +218,21 @@ impl<'a> Parser<'a> {
 
     fn unary(&mut self) -> Result<Expr, ParseError> {
         if let Some(op_token) = self.check(vec![Bang, Minus]) {
+            let start = self.previous().span;
             let op = if op_token == Bang {
                 UnOperator::Bang
             } else {
                 UnOperator::Minus
             };
             let right: Expr = self.unary()?;
-            return Ok(Expr::Unary {
-                op,
-                rh_expr: Box::new(right),
-            });
+            let span = start.to(right.span);
+            return Ok(Expr::new(
+                ExprKind::Unary {
+                    op,
+                    rh_expr: Box::new(right),
+                },
+                span,
+            ));
         }
         self.call()
     }
@@ -218,8 +240,36 @@ impl<'a> Parser<'a> {
     fn call(&mut self) -> Result<Expr, ParseError> {
         let mut expr = self.primary()?;
 
-        while self.check(vec![LeftParen]).is_some() {
-            expr = self.finish_call(expr)?;
+        loop {
+            match self.check(vec![LeftParen, Dot, LeftBracket]) {
+                Some(LeftParen) => expr = self.finish_call(expr)?,
+                Some(LeftBracket) => {
+                    let index = self.expression()?;
+                    self.consume(RightBracket, String::from("Expect ']' after index."))?;
+                    let span = self.span_from(expr.span);
+                    expr = Expr::new(
+                        ExprKind::Index {
+                            object: Box::new(expr),
+                            index: Box::new(index),
+                        },
+                        span,
+                    );
+                }
+                Some(Dot) => {
+                    let name = Rc::from(
+                        self.consume_identifier(String::from("Expect property name after '.'."))?,
+                    );
+                    let span = self.span_from(expr.span);
+                    expr = Expr::new(
+                        ExprKind::Get {
+                            object: Box::new(expr),
+                            name,
+                        },
+                        span,
+                    );
+                }
+                _ => break,
+            }
         }
 
         Ok(expr)
@@ -238,80 +288,247 @@ impl<'a> Parser<'a> {
                     break;
                 }
             }
-            self.consume(RightParen, String::from("Expect ')' after arguments"));
+            self.consume(RightParen, String::from("Expect ')' after arguments"))?;
         }
-        Ok(Expr::Call {
-            callee: Box::new(callee),
-            paren: RightParen,
-            arguments,
-        })
+        let span = self.span_from(callee.span);
+        Ok(Expr::new(
+            ExprKind::Call {
+                callee: Box::new(callee),
+                arguments,
+            },
+            span,
+        ))
     }
 
     fn primary(&mut self) -> Result<Expr, ParseError> {
-        let expr = match &self.advance().t_type {
-            False => Expr::Literal(Value::Boolean(false)),
-            True => Expr::Literal(Value::Boolean(true)),
-            Nil => Expr::Literal(Value::Nil),
-            NumLit(num) => Expr::Literal(Value::Number(*num)),
-            StringLit(s) => Expr::Literal(Value::String(Box::new(s.clone()))),
-            Identifier(name) => Expr::Variable(Rc::from(name.to_owned())),
+        let start = self.peek().span;
+        // advance() stays put at the end, so matching on the token it returns would
+        // see the one before the end again, e.g. the `[` of `print [`
+        if self.is_at_end() {
+            self.rulox
+                .error(Phase::Parse, start, String::from("Expect expression."));
+            return Err(ParseError);
+        }
+        let kind = match &self.advance().t_type {
+            False => ExprKind::Literal(Value::Boolean(false)),
+            True => ExprKind::Literal(Value::Boolean(true)),
+            Nil => ExprKind::Literal(Value::Nil),
+            This => ExprKind::This { id: next_expr_id() },
+            Super => {
+                self.consume(Dot, String::from("Expect '.' after 'super'."))?;
+                let method = Rc::from(
+                    self.consume_identifier(String::from("Expect superclass method name."))?,
+                );
+                ExprKind::Super {
+                    id: next_expr_id(),
+                    method,
+                }
+            }
+            NumLit(num) => ExprKind::Literal(Value::Number(*num)),
+            StringLit(s) => ExprKind::Literal(Value::String(Box::new(s.clone()))),
+            Interpolation(s) => {
+                let prefix = s.clone();
+                self.interpolation(prefix, start)?
+            }
+            Identifier(name) => ExprKind::Variable {
+                id: next_expr_id(),
+                name: Rc::from(name.to_owned()),
+            },
+            LeftBracket => {
+                let mut elements = Vec::new();
+                if self.check(vec![RightBracket]).is_none() {
+                    loop {
+                        elements.push(self.expression()?);
+                        if self.check(vec![Comma]).is_none() {
+                            break;
+                        }
+                    }
+                    self.consume(
+                        RightBracket,
+                        String::from("Expect ']' after list elements."),
+                    )?;
+                }
+                ExprKind::List(elements)
+            }
+            LeftBrace => {
+                let mut entries = Vec::new();
+                if self.check(vec![RightBrace]).is_none() {
+                    loop {
+                        let key = self.expression()?;
+                        self.consume(Colon, String::from("Expect ':' after map key."))?;
+                        entries.push((key, self.expression()?));
+                        if self.check(vec![Comma]).is_none() {
+                            break;
+                        }
+                    }
+                    self.consume(RightBrace, String::from("Expect '}' after map entries."))?;
+                }
+                ExprKind::Map(entries)
+            }
+            // `λ` scans as `fun` too
+            Fun => {
+                self.consume(LeftParen, String::from("Expect '(' after 'fun'."))?;
+                let (parameters, body) = self.function_body("function")?;
+                ExprKind::Lambda {
+                    parameters,
+                    body: Rc::from(body),
+                }
+            }
             LeftParen => {
                 let expr = self.expression()?;
                 self.consume(
                     RightParen,
                     "Expect ')' after grouped expression.".to_string(),
-                );
-                expr
+                )?;
+                // The grouping's span includes its parentheses
+                expr.kind
             }
             _ => return Err(self.error("Expect expression.".to_string())),
         };
 
-        Ok(expr)
+        Ok(Expr::new(kind, self.span_from(start)))
     }
 
-    // declaration -> fun_declaration | var_declaration | statement
-    fn declaration(&mut self) -> Result<Stmt, ParseError> {
-        match self.check(vec![Var, Fun]) {
-            // Some(Class) => self.class_declaration(),
+    // "a${x}b" scans as Interpolation("a"), the tokens of x, then StringLit("b"), and is
+    // lowered into "a" + str(x) + "b", leaving out the empty pieces of the string
+    fn interpolation(&mut self, prefix: String, start: Span) -> Result<ExprKind, ParseError> {
+        let mut pieces = vec![string_literal(prefix, start)];
+        loop {
+            let expr = self.expression()?;
+            let span = expr.span;
+            let stringify = ExprKind::Unary {
+                op: UnOperator::Stringify,
+                rh_expr: Box::new(expr),
+            };
+            pieces.push(Expr::new(stringify, span));
+
+            let (rest, more) = match &self.peek().t_type {
+                Interpolation(s) => (s.clone(), true),
+                StringLit(s) => (s.clone(), false),
+                _ => return Err(self.error(String::from("Expect '}' after interpolation."))),
+            };
+            let span = self.advance().span;
+            pieces.push(string_literal(rest, span));
+            if !more {
+                break;
+            }
+        }
+
+        let mut pieces = pieces.into_iter().filter(
+            |piece| !matches!(&piece.kind, ExprKind::Literal(Value::String(s)) if s.is_empty()),
+        );
+        // There's at least one stringified expression left
+        let first = pieces.next().unwrap();
+        Ok(pieces
+            .fold(first, |expr, piece| binary(expr, BiOperator::Plus, piece))
+            .kind)
+    }
+
+    // A declaration containing a syntax error is left out of the tree entirely,
+    // and parsing resumes at the start of the next statement
+    fn declaration(&mut self) -> Option<Stmt> {
+        let start = self.current;
+        match self.try_declaration() {
+            Ok(stmt) => Some(stmt),
+            Err(_) => {
+                self.synchronize(start);
+                None
+            }
+        }
+    }
+
+    // declaration -> class_declaration | fun_declaration | var_declaration
+    //              | import_declaration | statement
+    fn try_declaration(&mut self) -> Result<Stmt, ParseError> {
+        // `fun` followed by `(` starts an anonymous function, used as an expression statement
+        if self.check_type(&Fun) && self.tokens[self.current + 1].t_type == LeftParen {
+            return self.statement();
+        }
+
+        match self.check(vec![Class, Var, Fun, Import]) {
+            Some(Class) => self.class_declaration(),
             Some(Var) => self.var_declaration(),
             Some(Fun) => self.function("function"),
+            Some(Import) => self.import_declaration(),
             _ => self.statement(),
         }
     }
 
     fn statement(&mut self) -> Result<Stmt, ParseError> {
-        match self.check(vec![If, Print, LeftBrace, While, For, Return]) {
+        match self.check(vec![
+            If, Print, LeftBrace, While, For, Return, Break, Continue, Throw, Try,
+        ]) {
             Some(For) => self.for_statement(),
             Some(If) => self.if_statement(),
             Some(Print) => self.print_stmt(),
             Some(While) => self.while_statement(),
-            Some(LeftBrace) => Ok(Stmt::Block(self.block()?)),
+            Some(LeftBrace) => {
+                let start = self.previous().span;
+                let statements = self.block()?;
+                Ok(Stmt::new(
+                    StmtKind::Block(statements),
+                    self.span_from(start),
+                ))
+            }
             Some(Return) => self.return_statement(),
+            Some(Break) => {
+                let start = self.previous().span;
+                self.consume(Semicolon, String::from("Expect ';' after 'break'."))?;
+                Ok(Stmt::new(StmtKind::Break, self.span_from(start)))
+            }
+            Some(Continue) => {
+                let start = self.previous().span;
+                self.consume(Semicolon, String::from("Expect ';' after 'continue'."))?;
+                Ok(Stmt::new(StmtKind::Continue, self.span_from(start)))
+            }
+            Some(Throw) => self.throw_statement(),
+            Some(Try) => self.try_statement(),
             _ => self.expr_stmt(),
         }
     }
 
     fn class_declaration(&mut self) -> Result<Stmt, ParseError> {
         // Class has already been consumed in declaration via self.check()
+        let start = self.previous().span;
         let name = Rc::from(self.consume_identifier(String::from("Expect class name."))?);
-        self.consume(LeftBrace, String::from("Expect '{' before class body."));
+
+        let superclass = match self.check(vec![Less]) {
+            Some(_) => {
+                let name =
+                    Rc::from(self.consume_identifier(String::from("Expect superclass name."))?);
+                Some(Expr::new(
+                    ExprKind::Variable {
+                        id: next_expr_id(),
+                        name,
+                    },
+                    self.previous().span,
+                ))
+            }
+            None => None,
+        };
+
+        self.consume(LeftBrace, String::from("Expect '{' before class body."))?;
 
         let mut methods = Vec::new();
         while self.peek().t_type != RightBrace && self.peek().t_type != Eof {
-            // while (!self.check(vec![RightBrace]).is_some() && !self.is_at_end()) {
             methods.push(self.function("method")?);
         }
 
-        self.consume(RightBrace, String::from("Expect '}' after class body."));
+        self.consume(RightBrace, String::from("Expect '}' after class body."))?;
 
-        Ok(Stmt::Class {
-            name: Rc::clone(&name),
-            methods,
-        })
+        Ok(Stmt::new(
+            StmtKind::Class {
+                name: Rc::clone(&name),
+                superclass,
+                methods,
+            },
+            self.span_from(start),
+        ))
     }
 
     fn var_declaration(&mut self) -> Result<Stmt, ParseError> {
         // Var has already been consumed in declaration via self.check()
+        let start = self.previous().span;
         let name = Rc::from(self.consume_identifier(String::from("Expect variable name."))?);
         let initializer = match self.check(vec![Equal]) {
             Some(_) => Some(self.expression()?),
@@ -321,13 +538,77 @@ impl<'a> Parser<'a> {
         self.consume(
             Semicolon,
             String::from("Expect ';' after variable declaration."),
-        );
-        Ok(Stmt::Var { name, initializer })
+        )?;
+        Ok(Stmt::new(
+            StmtKind::Var { name, initializer },
+            self.span_from(start),
+        ))
+    }
+
+    // import_declaration -> "import" ( IDENTIFIER "from" )? STRING ";"
+    fn import_declaration(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.previous().span;
+        let name = match self.peek().t_type {
+            Identifier(_) => {
+                let name = self.consume_identifier(String::from("Expect module name."))?;
+                // `from` is only special here, so it's still a valid name everywhere else
+                if !matches!(&self.peek().t_type, Identifier(word) if word == "from") {
+                    return Err(self.error(String::from("Expect 'from' after module name.")));
+                }
+                self.advance();
+                Some(name)
+            }
+            _ => None,
+        };
+        let path = match self.peek().t_type.clone() {
+            StringLit(path) => {
+                self.advance();
+                path
+            }
+            _ => return Err(self.error(String::from("Expect module path."))),
+        };
+        let name = match name.or_else(|| module_name(&path)) {
+            Some(name) => name,
+            None => {
+                let msg = format!("Can't name a module \"{}\", use 'import name from'.", path);
+                return Err(self.error(msg));
+            }
+        };
+        self.consume(Semicolon, String::from("Expect ';' after import."))?;
+        Ok(Stmt::new(
+            StmtKind::Import {
+                name: Rc::from(name),
+                path,
+            },
+            self.span_from(start),
+        ))
     }
 
     fn function(&mut self, kind: &'static str) -> Result<Stmt, ParseError> {
+        // Methods have no leading `fun`, so they start at their name
+        let start = match kind {
+            "method" => self.peek().span,
+            _ => self.previous().span,
+        };
         let name = Rc::from(self.consume_identifier(format!("Expect {} name", kind))?);
-        self.consume(LeftParen, format!("Expect '(' after {} name", kind));
+        self.consume(LeftParen, format!("Expect '(' after {} name", kind))?;
+        let (parameters, body) = self.function_body(kind)?;
+
+        Ok(Stmt::new(
+            StmtKind::Function {
+                name,
+                parameters,
+                body: Rc::from(body),
+            },
+            self.span_from(start),
+        ))
+    }
+
+    // The rest of a function after its opening `(`: the parameters, then the body in braces
+    fn function_body(
+        &mut self,
+        kind: &'static str,
+    ) -> Result<(Vec<Rc<str>>, Vec<Stmt>), ParseError> {
         let mut parameters = Vec::new();
         if self.check(vec![RightParen]).is_none() {
             loop {
@@ -342,47 +623,47 @@ impl<'a> Parser<'a> {
                 }
             }
 
-            self.consume(RightParen, String::from("Expect ')' after parameters."));
+            self.consume(RightParen, String::from("Expect ')' after parameters."))?;
         }
 
-        self.consume(LeftBrace, format!("Expect {{ before {} body.", kind));
+        self.consume(LeftBrace, format!("Expect {{ before {} body.", kind))?;
 
         let body = self.block()?;
-
-        Ok(Stmt::Function {
-            name,
-            parameters,
-            body,
-        })
+        Ok((parameters, body))
     }
 
     fn expr_stmt(&mut self) -> Result<Stmt, ParseError> {
         let expr: Expr = self.expression()?;
-        self.consume(Semicolon, String::from("Expect ';' after expression."));
-        Ok(Stmt::Expression(expr))
+        self.consume(Semicolon, String::from("Expect ';' after expression."))?;
+        let span = self.span_from(expr.span);
+        Ok(Stmt::new(StmtKind::Expression(expr), span))
     }
 
     fn print_stmt(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.previous().span;
         let value: Expr = self.expression()?;
-        self.consume(Semicolon, String::from("Expect ';' after value."));
-        Ok(Stmt::Print(value))
+        self.consume(Semicolon, String::from("Expect ';' after value."))?;
+        Ok(Stmt::new(StmtKind::Print(value), self.span_from(start)))
     }
 
     fn block(&mut self) -> Result<Vec<Stmt>, ParseError> {
         let mut statements = Vec::new();
 
         while self.peek().t_type != RightBrace && self.peek().t_type != Eof {
-            statements.push(self.declaration()?);
+            if let Some(stmt) = self.declaration() {
+                statements.push(stmt);
+            }
         }
 
-        self.consume(RightBrace, String::from("Expect '}' after block."));
+        self.consume(RightBrace, String::from("Expect '}' after block."))?;
         Ok(statements)
     }
 
     fn if_statement(&mut self) -> Result<Stmt, ParseError> {
-        self.consume(LeftParen, String::from("Expect '(' after 'if'."));
+        let start = self.previous().span;
+        self.consume(LeftParen, String::from("Expect '(' after 'if'."))?;
         let condition = self.expression()?;
-        self.consume(RightParen, String::from("Expect ')' after condition."));
+        self.consume(RightParen, String::from("Expect ')' after condition."))?;
 
         let then_branch = Box::new(self.statement()?);
         let else_branch = if self.check(vec![Else]).is_some() {
@@ -392,31 +673,40 @@ impl<'a> Parser<'a> {
         }
         .map(Box::new);
 
-        Ok(Stmt::If {
-            condition,
-            then_branch,
-            else_branch,
-        })
+        Ok(Stmt::new(
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            },
+            self.span_from(start),
+        ))
     }
 
     fn while_statement(&mut self) -> Result<Stmt, ParseError> {
-        self.consume(LeftParen, String::from("Expect '(' after 'while'."));
+        let start = self.previous().span;
+        self.consume(LeftParen, String::from("Expect '(' after 'while'."))?;
         let condition = self.expression()?;
         self.consume(
             RightParen,
             String::from("Expect ')' after while condition."),
-        );
+        )?;
 
         let body = self.statement()?;
 
-        Ok(Stmt::While {
-            condition,
-            body: Box::new(body),
-        })
+        Ok(Stmt::new(
+            StmtKind::While {
+                condition,
+                body: Box::new(body),
+                increment: None,
+            },
+            self.span_from(start),
+        ))
     }
 
     fn for_statement(&mut self) -> Result<Stmt, ParseError> {
-        self.consume(LeftParen, String::from("Expect '(' after 'for'."));
+        let start = self.previous().span;
+        self.consume(LeftParen, String::from("Expect '(' after 'for'."))?;
 
         let initializer = match self.check(vec![Semicolon, Var]) {
             Some(Semicolon) => None,
@@ -425,7 +715,12 @@ impl<'a> Parser<'a> {
         };
 
         let condition = if self.peek().t_type == Semicolon {
-            Expr::Literal(Value::Boolean(true))
+            // A missing condition is attributed to the empty space before its `;`
+            let position = self.peek().span.start;
+            Expr::new(
+                ExprKind::Literal(Value::Boolean(true)),
+                Span::new(position, position),
+            )
         } else {
             self.expression()?
         };
@@ -433,7 +728,7 @@ impl<'a> Parser<'a> {
         self.consume(
             Semicolon,
             String::from("Expect ';' after for loop condition."),
-        );
+        )?;
 
         let increment = if self.peek().t_type == RightParen {
             None
@@ -441,35 +736,94 @@ impl<'a> Parser<'a> {
             Some(self.expression()?)
         };
 
-        self.consume(RightParen, String::from("Expect ')' after for clauses."));
-
-        let mut body = self.statement()?;
+        self.consume(RightParen, String::from("Expect ')' after for clauses."))?;
 
-        if let Some(increment) = increment {
-            body = Stmt::Block(vec![body, Stmt::Expression(increment)]);
-        }
-
-        body = Stmt::While {
-            condition,
-            body: Box::new(body),
-        };
+        // The increment is kept on the loop itself rather than appended to the body,
+        // so that `continue` still runs it before re-checking the condition
+        let body = self.statement()?;
+        let mut body = Stmt::new(
+            StmtKind::While {
+                condition,
+                body: Box::new(body),
+                increment,
+            },
+            self.span_from(start),
+        );
 
         if let Some(initializer) = initializer {
-            body = Stmt::Block(vec![initializer, body]);
+            body = Stmt::new(
+                StmtKind::Block(vec![initializer, body]),
+                self.span_from(start),
+            );
         }
 
         Ok(body)
     }
 
     fn return_statement(&mut self) -> Result<Stmt, ParseError> {
-        let val = if self.check(vec![Semicolon]).is_none() {
-            self.expression()?
+        let start = self.previous().span;
+        let val = if self.check_type(&Semicolon) {
+            Expr::new(ExprKind::Literal(Value::Nil), start)
         } else {
-            Expr::Literal(Value::Nil)
+            self.expression()?
+        };
+
+        self.consume(Semicolon, String::from("Expect ';' after return value."))?;
+        Ok(Stmt::new(StmtKind::Return(val), self.span_from(start)))
+    }
+
+    fn throw_statement(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.previous().span;
+        let value = self.expression()?;
+        self.consume(Semicolon, String::from("Expect ';' after thrown value."))?;
+        Ok(Stmt::new(StmtKind::Throw(value), self.span_from(start)))
+    }
+
+    fn try_statement(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.previous().span;
+        self.consume(LeftBrace, String::from("Expect '{' after 'try'."))?;
+        let body = self.block()?;
+
+        let catch = match self.check(vec![Catch]) {
+            Some(_) => {
+                self.consume(LeftParen, String::from("Expect '(' after 'catch'."))?;
+                let name =
+                    Rc::from(self.consume_identifier(String::from("Expect error variable name."))?);
+                self.consume(
+                    RightParen,
+                    String::from("Expect ')' after error variable name."),
+                )?;
+                self.consume(LeftBrace, String::from("Expect '{' before catch body."))?;
+                Some((name, self.block()?))
+            }
+            None => None,
         };
 
-        self.consume(Semicolon, String::from("Expect ';' after return value."));
-        Ok(Stmt::Return(val))
+        let finally = match self.check(vec![Finally]) {
+            Some(_) => {
+                self.consume(LeftBrace, String::from("Expect '{' after 'finally'."))?;
+                Some(self.block()?)
+            }
+            None => None,
+        };
+
+        if catch.is_none() && finally.is_none() {
+            return Err(self.error(String::from("Expect 'catch' or 'finally' after try block.")));
+        }
+
+        Ok(Stmt::new(
+            StmtKind::Try {
+                body,
+                catch,
+                finally,
+            },
+            self.span_from(start),
+        ))
+    }
+
+    // Span from `start` up to the end of the last consumed token
+    fn span_from(&mut self, start: Span) -> Span {
+        start.to(self.previous().span)
     }
 
     fn check(&mut self, expected_types: Vec<TokenType>) -> Option<TokenType> {
@@ -513,11 +867,12 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn consume(&mut self, t_type: TokenType, msg: String) {
+    fn consume(&mut self, t_type: TokenType, msg: String) -> Result<(), ParseError> {
         if self.check_type(&t_type) {
             self.advance();
+            Ok(())
         } else {
-            self.error(msg);
+            Err(self.error(msg))
         }
     }
 
@@ -533,33 +888,66 @@ impl<'a> Parser<'a> {
 
     fn error(&mut self, msg: String) -> ParseError {
         // Poor handling of underflow, need to rewrite
-        if self.current > 0 {
-            let current_token = &self.tokens[self.current - 1];
-            self.rulox.error_token(
-                current_token.t_type.to_string(),
-                current_token.line,
-                current_token.col,
-                msg,
-            );
+        let span = if self.current > 0 {
+            self.tokens[self.current - 1].span
         } else {
-            let current_token = &self.tokens[0];
-            self.rulox
-                .error_token(current_token.t_type.to_string(), 1, current_token.col, msg);
-        }
+            self.tokens[0].span
+        };
+        self.rulox.error(Phase::Parse, span, msg);
         ParseError
     }
 
-    fn synchronize(&mut self) {
-        self.advance();
+    // Discards tokens until just after a `;`, or just before a keyword or brace that starts
+    // a statement or closes the enclosing block.
+    // At least one token is always consumed, so a bad token can't be parsed over and over.
+    fn synchronize(&mut self, start: usize) {
+        if self.current == start {
+            self.advance();
+        }
 
         while !self.is_at_end() {
-            // if self.previous().t_type == Semicolon { return; }
+            if self.previous().t_type == Semicolon {
+                return;
+            }
 
-            if [Eof, Class, Fun, Var, For, If, While, Print, Return].contains(&self.peek().t_type) {
-                break;
+            if [
+                Class, Fun, Var, For, If, While, Print, Return, Break, Continue, Throw, Try,
+                Import, LeftBrace, RightBrace,
+            ]
+            .contains(&self.peek().t_type)
+            {
+                return;
             }
 
             self.advance();
         }
     }
 }
+
+// Without a name, an import binds the module to its file name minus the extension,
+// e.g. `math` for "lib/math.lox", as long as that's a valid identifier
+fn module_name(path: &str) -> Option<String> {
+    let stem = Path::new(path).file_stem()?.to_str()?;
+    let mut chars = stem.chars();
+    let first = chars.next()?;
+    let valid = (first.is_alphabetic() || first == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&stem);
+    valid.then(|| stem.to_owned())
+}
+
+fn string_literal(s: String, span: Span) -> Expr {
+    Expr::new(ExprKind::Literal(Value::String(Box::new(s))), span)
+}
+
+fn binary(lh_expr: Expr, op: BiOperator, rh_expr: Expr) -> Expr {
+    let span = lh_expr.span.to(rh_expr.span);
+    Expr::new(
+        ExprKind::Binary {
+            lh_expr: Box::new(lh_expr),
+            op,
+            rh_expr: Box::new(rh_expr),
+        },
+        span,
+    )
+}
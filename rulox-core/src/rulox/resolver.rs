@@ -1,14 +1,43 @@
-use super::ast::{Expr, Stmt};
-use super::function::FunctionType;
+use super::ast::{Expr, ExprId, ExprKind, Stmt, StmtKind, Value};
+use super::diagnostic::{Diagnostic, Phase, Span};
+use super::function::{ClassType, FunctionType};
 use super::interpreter::Interpreter;
 use std::collections::HashMap;
+use std::fmt;
 use std::mem;
 use std::rc::Rc;
 
+// A local variable's slot is its position among the declarations of its scope,
+// which is also the order the interpreter defines it in at runtime
+struct Local {
+    slot: usize,
+    defined: bool,
+}
+
+// What a single variable reference was resolved to, kept for inspecting the resolver.
+// References that aren't found in any local scope are globals, looked up by name at runtime.
+#[derive(Debug, Clone)]
+pub struct Resolution {
+    pub name: Rc<str>,
+    pub span: Span,
+    // Scopes out from the reference, and slot within that scope
+    pub local: Option<(usize, usize)>,
+}
+
+#[derive(Default)]
+struct Scope {
+    locals: HashMap<Rc<str>, Local>,
+    slot_count: usize,
+}
+
 pub struct Resolver<'a> {
     interpreter: &'a mut Interpreter,
-    scopes: Vec<HashMap<Rc<str>, bool>>,
+    scopes: Vec<Scope>,
     current_function: FunctionType,
+    current_class: ClassType,
+    in_loop: bool,
+    diagnostics: Vec<Diagnostic>,
+    resolutions: Vec<Resolution>,
 }
 
 impl<'a> Resolver<'a> {
@@ -17,43 +46,56 @@ impl<'a> Resolver<'a> {
             interpreter,
             scopes: Vec::new(),
             current_function: FunctionType::None,
+            current_class: ClassType::None,
+            in_loop: false,
+            diagnostics: Vec::new(),
+            resolutions: Vec::new(),
         }
     }
 
-    pub fn resolve_source(&mut self, stmts: &[Stmt]) -> Result<(), ()> {
+    // Problems found here are static errors, so a program that has any must not be run
+    pub fn resolve_source(&mut self, stmts: &[Stmt]) -> Vec<Diagnostic> {
         for stmt in stmts {
             self.resolve_stmt(stmt.clone());
         }
-        Ok(())
+        mem::take(&mut self.diagnostics)
+    }
+
+    // Every reference resolved so far, in the order they appear in the source
+    pub fn take_resolutions(&mut self) -> Vec<Resolution> {
+        mem::take(&mut self.resolutions)
+    }
+
+    fn error(&mut self, span: Span, msg: &str) {
+        self.diagnostics.push(Diagnostic::error(
+            Phase::Resolve,
+            msg.to_owned(),
+            Some(span),
+        ));
     }
 
     fn resolve_stmt(&mut self, stmt: Stmt) {
-        match stmt {
-            Stmt::Expression(expr) | Stmt::Print(expr) => self.resolve_expr(expr),
-            Stmt::Block(statements) => {
-                self.begin_scope();
-                for stmt in statements {
-                    self.resolve_stmt(stmt);
-                }
-                self.end_scope();
-            }
-            Stmt::Var { name, initializer } => {
-                self.declare(Rc::clone(&name));
+        let span = stmt.span;
+        match stmt.kind {
+            StmtKind::Expression(expr) | StmtKind::Print(expr) => self.resolve_expr(expr),
+            StmtKind::Block(statements) => self.resolve_block(statements),
+            StmtKind::Var { name, initializer } => {
+                self.declare(Rc::clone(&name), span);
                 if let Some(initializer) = initializer {
                     self.resolve_expr(initializer);
                 }
                 self.define(Rc::clone(&name));
             }
-            Stmt::Function {
+            StmtKind::Function {
                 name,
                 parameters,
                 body,
             } => {
-                self.declare(Rc::clone(&name));
+                self.declare(Rc::clone(&name), span);
                 self.define(Rc::clone(&name));
-                self.resolve_function(parameters, body, FunctionType::Function);
+                self.resolve_function(span, parameters, &body, FunctionType::Function);
             }
-            Stmt::If {
+            StmtKind::If {
                 condition,
                 then_branch,
                 else_branch,
@@ -64,50 +106,152 @@ impl<'a> Resolver<'a> {
                     self.resolve_stmt(*e_branch);
                 }
             }
-            Stmt::While { condition, body } => {
+            StmtKind::While {
+                condition,
+                body,
+                increment,
+            } => {
                 self.resolve_expr(condition);
+                let enclosing_loop = mem::replace(&mut self.in_loop, true);
                 self.resolve_stmt(*body);
+                self.in_loop = enclosing_loop;
+                if let Some(increment) = increment {
+                    self.resolve_expr(increment);
+                }
+            }
+            StmtKind::Break => {
+                if !self.in_loop {
+                    self.error(span, "Can't use 'break' outside of a loop.");
+                }
             }
-            Stmt::Return(expr) => {
+            StmtKind::Continue => {
+                if !self.in_loop {
+                    self.error(span, "Can't use 'continue' outside of a loop.");
+                }
+            }
+            StmtKind::Return(expr) => {
                 if let FunctionType::None = self.current_function {
-                    eprintln!("Can't return from top-level code.");
+                    self.error(span, "Can't return from top-level code.");
+                }
+
+                if let FunctionType::Initializer = self.current_function {
+                    if !matches!(expr.kind, ExprKind::Literal(Value::Nil)) {
+                        self.error(span, "Can't return a value from an initializer.");
+                    }
                 }
 
                 self.resolve_expr(expr);
             }
-            Stmt::Class { name, .. } => {
-                self.declare(Rc::clone(&name));
+            StmtKind::Throw(expr) => self.resolve_expr(expr),
+            StmtKind::Import { name, .. } => {
+                self.declare(Rc::clone(&name), span);
+                self.define(name);
+            }
+            StmtKind::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                self.resolve_block(body);
+                // The error is bound in the same scope as the handler's own declarations
+                if let Some((name, handler)) = catch {
+                    self.begin_scope();
+                    self.declare(Rc::clone(&name), span);
+                    self.define(name);
+                    for stmt in handler {
+                        self.resolve_stmt(stmt);
+                    }
+                    self.end_scope();
+                }
+                if let Some(finally) = finally {
+                    self.resolve_block(finally);
+                }
+            }
+            StmtKind::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                let enclosing_class = mem::replace(&mut self.current_class, ClassType::Class);
+
+                self.declare(Rc::clone(&name), span);
                 self.define(Rc::clone(&name));
+
+                let has_superclass = superclass.is_some();
+                if let Some(superclass) = superclass {
+                    if let ExprKind::Variable {
+                        name: ref superclass_name,
+                        ..
+                    } = superclass.kind
+                    {
+                        if *superclass_name == name {
+                            self.error(superclass.span, "A class can't inherit from itself.");
+                        }
+                    }
+                    self.current_class = ClassType::Subclass;
+                    self.resolve_expr(superclass);
+
+                    self.begin_scope();
+                    self.declare(Rc::from("super"), span);
+                    self.define(Rc::from("super"));
+                }
+
+                self.begin_scope();
+                self.declare(Rc::from("this"), span);
+                self.define(Rc::from("this"));
+                for method in methods {
+                    if let StmtKind::Function {
+                        name,
+                        parameters,
+                        body,
+                    } = method.kind
+                    {
+                        let f_type = if &*name == "init" {
+                            FunctionType::Initializer
+                        } else {
+                            FunctionType::Method
+                        };
+                        self.resolve_function(method.span, parameters, &body, f_type);
+                    }
+                }
+                self.end_scope();
+
+                if has_superclass {
+                    self.end_scope();
+                }
+
+                self.current_class = enclosing_class;
             }
         }
     }
 
     fn resolve_expr(&mut self, expr: Expr) {
-        match expr {
-            Expr::Variable(name) => {
+        let span = expr.span;
+        match expr.kind {
+            ExprKind::Variable { id, name } => {
                 if let Some(scope) = self.scopes.last() {
-                    if let Some(initialized) = scope.get(&name) {
-                        if *initialized == false {
-                            eprintln!("Can't read local variable in its own initializer.");
+                    if let Some(local) = scope.locals.get(&name) {
+                        if !local.defined {
+                            self.error(span, "Can't read local variable in its own initializer.");
                         }
                     }
                 }
-                self.resolve_local(name);
+                self.resolve_local(id, name, span);
             }
-            Expr::Assign { name, value } => {
+            ExprKind::Assign { id, name, value } => {
                 self.resolve_expr(*value);
-                self.resolve_local(name);
+                self.resolve_local(id, name, span);
             }
-            Expr::Binary {
+            ExprKind::Binary {
                 lh_expr, rh_expr, ..
             } => {
                 self.resolve_expr(*lh_expr);
                 self.resolve_expr(*rh_expr);
             }
-            Expr::Unary { rh_expr, .. } => {
+            ExprKind::Unary { rh_expr, .. } => {
                 self.resolve_expr(*rh_expr);
             }
-            Expr::Call {
+            ExprKind::Call {
                 callee, arguments, ..
             } => {
                 self.resolve_expr(*callee);
@@ -115,8 +259,59 @@ impl<'a> Resolver<'a> {
                     self.resolve_expr(arg);
                 }
             }
-            Expr::Literal(_) => {}
-            Expr::Logical {
+            ExprKind::Get { object, .. } => {
+                self.resolve_expr(*object);
+            }
+            ExprKind::Set { object, value, .. } => {
+                self.resolve_expr(*value);
+                self.resolve_expr(*object);
+            }
+            ExprKind::List(elements) => {
+                for element in elements {
+                    self.resolve_expr(element);
+                }
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.resolve_expr(key);
+                    self.resolve_expr(value);
+                }
+            }
+            ExprKind::Index { object, index } => {
+                self.resolve_expr(*object);
+                self.resolve_expr(*index);
+            }
+            ExprKind::SetIndex {
+                object,
+                index,
+                value,
+            } => {
+                self.resolve_expr(*value);
+                self.resolve_expr(*object);
+                self.resolve_expr(*index);
+            }
+            ExprKind::This { id } => {
+                if let ClassType::None = self.current_class {
+                    self.error(span, "Can't use 'this' outside of a class.");
+                    return;
+                }
+                self.resolve_local(id, Rc::from("this"), span);
+            }
+            ExprKind::Super { id, .. } => {
+                match self.current_class {
+                    ClassType::None => self.error(span, "Can't use 'super' outside of a class."),
+                    ClassType::Class => {
+                        self.error(span, "Can't use 'super' in a class with no superclass.")
+                    }
+                    ClassType::Subclass => {}
+                }
+                self.resolve_local(id, Rc::from("super"), span);
+            }
+            ExprKind::Lambda { parameters, body } => {
+                self.resolve_function(span, parameters, &body, FunctionType::Function);
+            }
+            ExprKind::Literal(_) => {}
+            ExprKind::Logical {
                 lh_expr, rh_expr, ..
             } => {
                 self.resolve_expr(*lh_expr);
@@ -125,52 +320,97 @@ impl<'a> Resolver<'a> {
         }
     }
 
+    fn resolve_block(&mut self, statements: Vec<Stmt>) {
+        self.begin_scope();
+        for stmt in statements {
+            self.resolve_stmt(stmt);
+        }
+        self.end_scope();
+    }
+
     fn begin_scope(&mut self) {
-        self.scopes.push(HashMap::new());
+        self.scopes.push(Scope::default());
     }
 
     fn end_scope(&mut self) {
         self.scopes.pop();
     }
 
-    fn declare(&mut self, name: Rc<str>) {
+    fn declare(&mut self, name: Rc<str>, span: Span) {
         if let Some(scope) = self.scopes.last_mut() {
-            if scope.insert(Rc::clone(&name), false).is_some() {
-                eprintln!("Variable with this name already declared in this scope.");
-                return;
+            let local = Local {
+                slot: scope.slot_count,
+                defined: false,
+            };
+            scope.slot_count += 1;
+            if scope.locals.insert(Rc::clone(&name), local).is_some() {
+                self.error(
+                    span,
+                    "Variable with this name already declared in this scope.",
+                );
             }
         }
     }
 
     fn define(&mut self, name: Rc<str>) {
         if let Some(scope) = self.scopes.last_mut() {
-            scope.insert(Rc::clone(&name), true);
+            if let Some(local) = scope.locals.get_mut(&name) {
+                local.defined = true;
+            }
         }
     }
 
-    fn resolve_local(&mut self, name: Rc<str>) {
-        for (i, scope) in self.scopes.iter().rev().enumerate() {
-            if scope.contains_key(&name) {
-                self.interpreter.resolve(Rc::clone(&name), i);
-            }
+    // Records how many scopes out from the innermost one the variable was found in,
+    // along with its slot in that scope.
+    // Anything not found is assumed to be global and left unresolved.
+    fn resolve_local(&mut self, id: ExprId, name: Rc<str>, span: Span) {
+        let local = self
+            .scopes
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(i, scope)| scope.locals.get(&name).map(|local| (i, local.slot)));
+
+        if let Some((depth, slot)) = local {
+            self.interpreter.resolve(id, depth, slot);
         }
+        self.resolutions.push(Resolution { name, span, local });
     }
 
     fn resolve_function(
         &mut self,
+        span: Span,
         parameters: Vec<Rc<str>>,
-        body: Vec<Stmt>,
+        body: &[Stmt],
         f_type: FunctionType,
     ) {
         let enclosing_function = mem::replace(&mut self.current_function, f_type);
+        // A loop around a function declaration doesn't extend into its body
+        let enclosing_loop = mem::replace(&mut self.in_loop, false);
 
         self.begin_scope();
         for param in parameters {
-            self.declare(Rc::clone(&param));
+            self.declare(Rc::clone(&param), span);
             self.define(Rc::clone(&param));
         }
-        self.resolve_stmt(Stmt::Block(body));
+        // The body runs in the same environment as the parameters, see LoxFunction::call
+        for stmt in body {
+            self.resolve_stmt(stmt.clone());
+        }
         self.end_scope();
         self.current_function = enclosing_function;
+        self.in_loop = enclosing_loop;
+    }
+}
+
+// e.g. "3:12 count -> depth 1, slot 0" or "5:1 print_all -> global"
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let start = self.span.start;
+        write!(f, "{}:{} {} -> ", start.line, start.col, self.name)?;
+        match self.local {
+            Some((depth, slot)) => write!(f, "depth {}, slot {}", depth, slot),
+            None => write!(f, "global"),
+        }
     }
 }
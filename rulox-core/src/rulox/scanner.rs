@@ -1,16 +1,31 @@
+use super::diagnostic::{Phase, Position, Span};
 use super::token::Token;
 use super::token::TokenType;
 use super::token::TokenType::*;
 use super::Rulox;
 
+// Words the scanner turns into their own token types rather than identifiers
+pub const KEYWORDS: [&str; 23] = [
+    "and", "break", "catch", "class", "continue", "else", "false", "finally", "for", "fun", "if",
+    "import", "nil", "or", "print", "return", "super", "this", "throw", "true", "try", "var",
+    "while",
+];
+
 pub struct Scanner<'a> {
     source: Vec<char>,
     rulox: &'a mut Rulox,
     tokens: Vec<Token>,
     start: usize,
+    // Where the lexeme beginning at `start` sits in the source, for diagnostics
+    start_position: Position,
     current: usize,
+    // Byte offset of `current`, which indexes chars rather than bytes
+    offset: usize,
     col: usize,
     line: usize,
+    // Interpolations whose expression is being scanned, innermost last, each with the
+    // number of braces opened inside it that are still unclosed
+    interpolations: Vec<usize>,
 }
 
 // Consider reworking source as &'a str and iterating through it with .as_bytes()
@@ -21,9 +36,12 @@ impl<'a> Scanner<'a> {
             source: source_code.chars().collect(),
             tokens: Vec::new(),
             start: 0,
+            start_position: Position::default(),
             current: 0,
+            offset: 0,
             col: 0,
             line: 1,
+            interpolations: Vec::new(),
         }
     }
 
@@ -34,13 +52,20 @@ impl<'a> Scanner<'a> {
             // Start refers to beginning of each token,
             // Current is a cursor that can look ahead and pick up contents of literals
             self.start = self.current;
+            self.start_position = self.position();
             self.scan_token();
         }
 
+        if !self.interpolations.is_empty() {
+            self.rulox.unterminated = true;
+            self.error("Unterminated string.".to_string());
+        }
+
         self.tokens.push(Token {
             t_type: Eof,
             line: self.line,
-            col: self.source.len() - 1,
+            col: self.col,
+            span: Span::new(self.position(), self.position()),
         });
 
         self.tokens
@@ -58,9 +83,28 @@ impl<'a> Scanner<'a> {
         match c {
             '(' => self.add_token(LeftParen),
             ')' => self.add_token(RightParen),
-            '{' => self.add_token(LeftBrace),
-            '}' => self.add_token(RightBrace),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.add_token(LeftBrace);
+            }
+            '}' => match self.interpolations.last_mut() {
+                // The brace closing an interpolation carries on with the rest of the string
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string();
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.add_token(RightBrace);
+                }
+                None => self.add_token(RightBrace),
+            },
+            '[' => self.add_token(LeftBracket),
+            ']' => self.add_token(RightBracket),
             ',' => self.add_token(Comma),
+            ':' => self.add_token(Colon),
             '.' => self.add_token(Dot),
             '-' => self.add_token(Minus),
             '+' => self.add_token(Plus),
@@ -119,16 +163,13 @@ impl<'a> Scanner<'a> {
             '"' => self.string(),
             c => {
                 // We can make use of u8: is_ascii_digit() and is_ascii_alphanumeric()
-                if c.is_digit(10) {
+                if c.is_ascii_digit() {
                     self.number();
                 // If a lexeme begins with a letter or underscore, we can assume its an identifier
                 } else if c.is_alphabetic() || c == '_' {
                     self.identifier();
                 } else {
-                    self.rulox.error_line(
-                        self.line,
-                        format!("Unexpected character: {:?} = {:?}", c, c as char),
-                    );
+                    self.error(format!("Unexpected character: {:?}", c));
                 }
             }
         }
@@ -140,19 +181,39 @@ impl<'a> Scanner<'a> {
     fn advance(&mut self) -> char {
         let current_char = self.source[self.current];
         self.current += 1;
+        self.offset += current_char.len_utf8();
         self.col += 1;
         current_char
     }
 
+    fn position(&self) -> Position {
+        Position {
+            offset: self.offset,
+            line: self.line,
+            col: self.col + 1,
+        }
+    }
+
     // At the moment adding a token consists of pushing a Token with the proper type and the line #
     fn add_token(&mut self, t_type: TokenType) {
         self.tokens.push(Token {
             t_type,
             line: self.line,
             col: self.col,
+            span: Span::new(self.start_position, self.position()),
         });
     }
 
+    // Reports an error spanning the lexeme scanned so far
+    fn error(&mut self, msg: String) {
+        self.error_from(self.start_position, msg);
+    }
+
+    fn error_from(&mut self, start: Position, msg: String) {
+        let span = Span::new(start, self.position());
+        self.rulox.error(Phase::Scan, span, msg);
+    }
+
     // Check to see if the next character belongs to the two-character token types
     // If so, advance the current cursor
     fn match_next(&mut self, expected_char: char) -> bool {
@@ -165,6 +226,7 @@ impl<'a> Scanner<'a> {
         }
 
         self.current += 1;
+        self.offset += expected_char.len_utf8();
         self.col += 1;
         true
     }
@@ -195,16 +257,15 @@ impl<'a> Scanner<'a> {
     // that determine number of characters to advance, although perhaps over-engineering
     fn block_comment(&mut self) {
         while self.peek() != '*' && self.peek_second() != '/' && !self.is_at_end() {
-            if self.peek() == '\n' {
+            if self.advance() == '\n' {
                 self.line += 1;
                 self.col = 0;
             }
-            self.advance();
         }
 
         if self.is_at_end() {
-            self.rulox
-                .error_line(self.line, "Unterminated block comment.".to_string());
+            self.rulox.unterminated = true;
+            self.error("Unterminated block comment.".to_string());
             return;
         }
         // We have reached the closing */, advance the current cursor ahead
@@ -214,56 +275,151 @@ impl<'a> Scanner<'a> {
         self.advance();
     }
 
-    // Upon consuming " advance current cursor until closing " or EOF
-    // Since self.start remains at the opening " we can use it to consume the entire string
-    // .advance() also returns a byte but we are not interested in it here
-    // Could probably split functionality of "advance cursor" and "consume byte"
+    // Upon consuming " (or the } ending an interpolation) advance the current cursor until
+    // the closing " or EOF, building up the string's value with its escapes replaced.
+    // A ${ ends the token early: what follows is scanned as an expression, and the string
+    // carries on from the } that closes it.
     fn string(&mut self) {
-        while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
-                self.col = 0;
+        let mut value = String::new();
+        loop {
+            if self.is_at_end() {
+                self.rulox.unterminated = true;
+                self.error("Unterminated string.".to_string());
+                // This covers the strings interpolating it too, scan_tokens needn't report them
+                self.interpolations.clear();
+                return;
+            }
+
+            let start = self.position();
+            match self.advance() {
+                '"' => break,
+                '\n' => {
+                    self.line += 1;
+                    self.col = 0;
+                    value.push('\n');
+                }
+                '\\' => {
+                    if let Some(c) = self.escape(start) {
+                        value.push(c);
+                    }
+                }
+                '$' if self.peek() == '{' => {
+                    self.advance();
+                    self.interpolations.push(0);
+                    self.add_token(Interpolation(value));
+                    return;
+                }
+                c => value.push(c),
             }
-            self.advance();
         }
+        self.add_token(StringLit(value));
+    }
 
+    // The character a backslash escape stands for, reporting an error for an invalid one
+    fn escape(&mut self, start: Position) -> Option<char> {
+        // The string is unterminated, which is reported instead
         if self.is_at_end() {
-            self.rulox
-                .error_line(self.line, "Unterminated string.".to_string());
-            return;
+            return None;
         }
-        // We have reached the close "", advance the current cursor ahead
-        self.advance();
 
-        // Trim surrounding quotes
-        // Need a reference to the source, otherwise it doesn't know the correct size
-        let value: String = self.source[self.start + 1..self.current - 1]
-            .iter()
-            .collect();
-        self.add_token(StringLit(value));
+        let c = match self.advance() {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '"' => '"',
+            '\\' => '\\',
+            '$' => '$',
+            'u' => return self.unicode_escape(start),
+            c => {
+                self.error_from(start, format!("Invalid escape sequence '\\{}'.", c));
+                return None;
+            }
+        };
+        Some(c)
     }
 
-    // Like with string(), once we know we're in a number, we consume the full literal
+    // \u{...} names a Unicode scalar value with one to six hex digits
+    fn unicode_escape(&mut self, start: Position) -> Option<char> {
+        let mut digits = String::new();
+        let braced = self.match_next('{');
+        if braced {
+            while self.peek().is_ascii_hexdigit() {
+                digits.push(self.advance());
+            }
+        }
+
+        let c = if braced && self.match_next('}') && digits.len() <= 6 {
+            u32::from_str_radix(&digits, 16)
+                .ok()
+                .and_then(char::from_u32)
+        } else {
+            None
+        };
+        if c.is_none() {
+            self.error_from(start, "Invalid unicode escape sequence.".to_string());
+        }
+        c
+    }
+
+    // Like with string(), once we know we're in a number, we consume the full literal:
+    // a decimal with an optional fraction and exponent (`6.02e23`), or an integer in hex,
+    // octal or binary (`0xFF`, `0o17`, `0b1010`). Underscores can separate digits (`1_000`).
     fn number(&mut self) {
-        while self.peek().is_ascii_digit() {
+        let radix = match (self.source[self.start], self.peek()) {
+            ('0', 'x') | ('0', 'X') => 16,
+            ('0', 'o') | ('0', 'O') => 8,
+            ('0', 'b') | ('0', 'B') => 2,
+            _ => 10,
+        };
+        if radix != 10 {
             self.advance();
         }
+        self.digits(radix);
+
+        if radix == 10 {
+            // Look for a fractional part
+            if self.peek() == '.' && self.peek_second().is_ascii_digit() {
+                self.advance();
+                self.digits(10);
+            }
+            if self.peek() == 'e' || self.peek() == 'E' {
+                self.advance();
+                if self.peek() == '+' || self.peek() == '-' {
+                    self.advance();
+                }
+                self.digits(10);
+            }
+        }
 
-        // Look for a fractional part
-        if self.peek() == '.' && self.peek_second().is_ascii_digit() {
-            // Consume the '.'
+        // Letters straight after a number, like in `0b102` or `12px`, make it malformed
+        // rather than starting an identifier
+        while self.peek().is_alphanumeric() {
             self.advance();
+        }
 
-            while self.peek().is_ascii_digit() {
-                self.advance();
+        let lexeme = &self.source[self.start..self.current];
+        match number_value(lexeme, radix) {
+            Some(value) => self.add_token(NumLit(value)),
+            None => {
+                let kind = match radix {
+                    16 => "hexadecimal",
+                    8 => "octal",
+                    2 => "binary",
+                    _ => "number",
+                };
+                let lexeme: String = lexeme.iter().collect();
+                self.error(format!("Invalid {} literal '{}'.", kind, lexeme));
+                // Parsing goes on as if the literal was fine, to find any other errors
+                self.add_token(NumLit(0.0));
             }
         }
+    }
 
-        // Span from the start of the literal to the current cursor
-        // With Rust we need to unwrap both constructing a &str from bytes
-        // And parsing that &str as a number
-        let num_string: String = self.source[self.start..self.current].iter().collect();
-        self.add_token(NumLit(num_string.parse().unwrap()));
+    fn digits(&mut self, radix: u32) {
+        while self.peek().is_digit(radix) || self.peek() == '_' {
+            self.advance();
+        }
     }
 
     // After strings and numbers, the remaining case is alphanumeric identifiers
@@ -296,8 +452,43 @@ impl<'a> Scanner<'a> {
             "while" => TokenType::While,
             "break" => TokenType::Break,
             "continue" => TokenType::Continue,
+            "throw" => TokenType::Throw,
+            "try" => TokenType::Try,
+            "catch" => TokenType::Catch,
+            "finally" => TokenType::Finally,
+            "import" => TokenType::Import,
             name => Identifier(name.to_owned()),
         };
         self.add_token(t_type);
     }
 }
+
+// The value of a scanned number literal, or None if it's malformed
+fn number_value(lexeme: &[char], radix: u32) -> Option<f64> {
+    // Underscores can only go between two digits
+    let separated = lexeme.iter().enumerate().all(|(i, c)| {
+        *c != '_'
+            || (i > 0
+                && lexeme[i - 1].is_digit(radix)
+                && lexeme.get(i + 1).is_some_and(|next| next.is_digit(radix)))
+    });
+    if !separated {
+        return None;
+    }
+
+    let value = if radix == 10 {
+        let text: String = lexeme.iter().filter(|c| **c != '_').collect();
+        text.parse().ok()?
+    } else {
+        // Past the prefix, everything left is a digit
+        let digits = lexeme[2..].iter().filter(|c| **c != '_');
+        let mut value: Option<f64> = None;
+        for digit in digits {
+            let digit = f64::from(digit.to_digit(radix)?);
+            value = Some(value.unwrap_or(0.0) * f64::from(radix) + digit);
+        }
+        value?
+    };
+    // e.g. `1e400`
+    value.is_finite().then_some(value)
+}
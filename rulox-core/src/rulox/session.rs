@@ -0,0 +1,293 @@
+// Embedding API, for host applications that use Lox as a scripting layer.
+//
+// A Session keeps one program alive across any number of `eval` calls, like a REPL does,
+// and lets the host read and write its globals and call its functions with Rust values:
+//
+//     let mut session = Session::new();
+//     session.eval("fun area(w, h) { return w * h; }")?;
+//     session.set("side", 3.0);
+//     let area = f64::from_value(session.eval("area(side, 2);")?)?;
+//     let area = session.call("area", vec![Value::from(4.0), Value::from(5.0)])?;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+
+use super::ast::{MapKey, StmtKind, Value};
+use super::diagnostic::{Diagnostic, Phase};
+use super::function::{NativeContext, NativeFunction};
+use super::interpreter::RuntimeError;
+use super::modules::Loader;
+use super::output::{Output, Writer};
+use super::{Backend, Rulox};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    // The source couldn't be run at all, with every problem that was found in it
+    Compile(Vec<Diagnostic>),
+    // The program stopped on an uncaught runtime error
+    Runtime(Diagnostic),
+    // No global with this name has been defined
+    Undefined(String),
+    // A Lox value didn't convert to the Rust type the host asked for
+    Type {
+        expected: &'static str,
+        found: String,
+    },
+    // Rust data didn't fit in Lox values or the other way around, see the `convert` module
+    Convert(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Compile(diagnostics) => {
+                let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+                write!(f, "{}", messages.join("\n"))
+            }
+            Error::Runtime(diagnostic) => write!(f, "{}", diagnostic),
+            Error::Undefined(name) => write!(f, "Undefined variable '{}'", name),
+            Error::Type { expected, found } => write!(f, "Expected {}, found {}", expected, found),
+            Error::Convert(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub struct Session {
+    rulox: Rulox,
+}
+
+impl Session {
+    // A session on the tree-walking interpreter, printing to stdout
+    pub fn new() -> Session {
+        Session::with_backend(Backend::TreeWalk)
+    }
+
+    pub fn with_backend(backend: Backend) -> Session {
+        let mut rulox = Rulox::new(String::new());
+        rulox.set_backend(backend);
+        rulox.set_output(Writer(io::stdout()));
+        Session { rulox }
+    }
+
+    pub fn set_output(&mut self, output: impl Output + 'static) {
+        self.rulox.set_output(output);
+    }
+
+    pub fn set_loader(&mut self, loader: impl Loader + 'static) {
+        self.rulox.set_loader(loader);
+    }
+
+    // Runs `source` in the session. Its result is the value of a bare expression at the
+    // very end, e.g. `x + 1;` or `f(2);`, or nil if it doesn't end with one.
+    pub fn eval(&mut self, source: &str) -> Result<Value, Error> {
+        let rulox = &mut self.rulox;
+        rulox.load(source.to_owned());
+        rulox.tokenize();
+        rulox.parse();
+        rulox.resolve();
+        if rulox.had_errors {
+            let errors = rulox.diagnostics().iter().filter(|d| d.is_error());
+            return Err(Error::Compile(errors.cloned().collect()));
+        }
+
+        // Returning the trailing expression from the script hands its value back from
+        // either backend. The resolver already ran, so it won't warn about this `return`.
+        if let Some(stmt) = rulox.parse_tree.last_mut() {
+            if let StmtKind::Expression(expr) = &stmt.kind {
+                stmt.kind = StmtKind::Return(expr.clone());
+            }
+        }
+
+        rulox
+            .execute()
+            .map_err(|diagnostic| match diagnostic.phase {
+                Phase::Runtime => Error::Runtime(diagnostic),
+                _ => Error::Compile(vec![diagnostic]),
+            })
+    }
+
+    // Warnings about the source passed to the last `eval`, which didn't stop it from running
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        self.rulox.diagnostics()
+    }
+
+    // Reads a global, e.g. `let count: f64 = session.get("count")?;`
+    pub fn get<T: FromValue>(&self, name: &str) -> Result<T, Error> {
+        let value = match self.rulox.backend {
+            Backend::TreeWalk => self.rulox.interpreter.globals.borrow().get(name).ok(),
+            Backend::Bytecode => self.rulox.vm.get_global(name),
+        };
+        T::from_value(value.ok_or_else(|| Error::Undefined(name.to_owned()))?)
+    }
+
+    // Defines a global, or replaces the value of an existing one
+    pub fn set(&mut self, name: &str, value: impl Into<Value>) {
+        let (name, value) = (Rc::from(name), value.into());
+        match self.rulox.backend {
+            Backend::TreeWalk => self
+                .rulox
+                .interpreter
+                .globals
+                .borrow_mut()
+                .define(name, value),
+            Backend::Bytecode => self.rulox.vm.define_global(name, value),
+        }
+    }
+
+    // Calls a global function, or a class to create an instance of it
+    pub fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, Error> {
+        let callee = self.get(name)?;
+        self.call_value(callee, args)
+    }
+
+    // Calls a function or class the host got hold of, e.g. one returned by `eval`
+    pub fn call_value(&mut self, callee: Value, args: Vec<Value>) -> Result<Value, Error> {
+        let result = match self.rulox.backend {
+            Backend::TreeWalk => self.rulox.interpreter.call(callee, args),
+            Backend::Bytecode => self.rulox.vm.call(callee, args),
+        };
+        result.map_err(Error::Runtime)
+    }
+
+    // Exposes a Rust closure to Lox code as a global function
+    pub fn register_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&mut dyn NativeContext, Vec<Value>) -> Result<Value, RuntimeError> + 'static,
+    {
+        let native = NativeFunction::new(name, arity, function);
+        self.set(name, Value::Callable(Rc::new(native)));
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// -----------------------
+// ----- Conversions -----
+// -----------------------
+
+impl From<f64> for Value {
+    fn from(num: f64) -> Self {
+        Value::Number(num)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(Box::new(s.to_owned()))
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(Box::new(s))
+    }
+}
+
+impl From<()> for Value {
+    fn from(_: ()) -> Self {
+        Value::Nil
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(option: Option<T>) -> Self {
+        option.map_or(Value::Nil, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(elements: Vec<T>) -> Self {
+        let elements = elements.into_iter().map(Into::into).collect();
+        Value::List(Rc::new(RefCell::new(elements)))
+    }
+}
+
+// Lox maps can have other keys too, but string keys are what a host usually has
+impl<T: Into<Value>> From<HashMap<String, T>> for Value {
+    fn from(entries: HashMap<String, T>) -> Self {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| (MapKey::String(Box::new(key)), value.into()))
+            .collect();
+        Value::Map(Rc::new(RefCell::new(entries)))
+    }
+}
+
+// Rust types a Lox value can be read back as
+pub trait FromValue: Sized {
+    fn from_value(value: Value) -> Result<Self, Error>;
+}
+
+fn type_error<T>(expected: &'static str, value: &Value) -> Result<T, Error> {
+    Err(Error::Type {
+        expected,
+        found: value.to_string(),
+    })
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        Ok(value)
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        match value {
+            Value::Number(num) => Ok(num),
+            value => type_error("a number", &value),
+        }
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        match value {
+            Value::Boolean(b) => Ok(b),
+            value => type_error("a boolean", &value),
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        match value {
+            Value::String(s) => Ok(*s),
+            value => type_error("a string", &value),
+        }
+    }
+}
+
+// nil reads as None
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        match value {
+            Value::Nil => Ok(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        match value {
+            Value::List(list) => list.borrow().iter().cloned().map(T::from_value).collect(),
+            value => type_error("a list", &value),
+        }
+    }
+}
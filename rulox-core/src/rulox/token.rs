@@ -1,3 +1,4 @@
+use super::diagnostic::Span;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
@@ -8,7 +9,10 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
     Dot,
     Minus,
     Plus,
@@ -28,7 +32,10 @@ pub enum TokenType {
 
     // Literals
     StringLit(String), // Consider adding an enum for literals
-    NumLit(f64),       // Lox uses double-precision (64-bit) floats
+    // The part of a string before a `${`, which is followed by the tokens of the
+    // interpolated expression, then the rest of the string from its closing `}`
+    Interpolation(String),
+    NumLit(f64), // Lox uses double-precision (64-bit) floats
     Identifier(String),
 
     // Keywords
@@ -50,6 +57,11 @@ pub enum TokenType {
     While,
     Break,
     Continue,
+    Throw,
+    Try,
+    Catch,
+    Finally,
+    Import,
 
     Eof,
 }
@@ -61,7 +73,10 @@ impl fmt::Display for TokenType {
             TokenType::RightParen => write!(f, ")"),
             TokenType::LeftBrace => write!(f, "{{"),
             TokenType::RightBrace => write!(f, "}}"),
+            TokenType::LeftBracket => write!(f, "["),
+            TokenType::RightBracket => write!(f, "]"),
             TokenType::Comma => write!(f, ","),
+            TokenType::Colon => write!(f, ":"),
             TokenType::Dot => write!(f, "."),
             TokenType::Minus => write!(f, "-"),
             TokenType::Plus => write!(f, "+"),
@@ -77,6 +92,7 @@ impl fmt::Display for TokenType {
             TokenType::Less => write!(f, "<"),
             TokenType::LessEqual => write!(f, "<="),
             TokenType::StringLit(lit) => write!(f, "\"{}\"", lit),
+            TokenType::Interpolation(lit) => write!(f, "\"{}${{", lit),
             TokenType::NumLit(lit) => write!(f, "{}", lit),
             TokenType::Identifier(lit) => write!(f, "{}", lit),
             TokenType::And => write!(f, "and"),
@@ -97,6 +113,11 @@ impl fmt::Display for TokenType {
             TokenType::While => write!(f, "while"),
             TokenType::Break => write!(f, "break"),
             TokenType::Continue => write!(f, "continue"),
+            TokenType::Throw => write!(f, "throw"),
+            TokenType::Try => write!(f, "try"),
+            TokenType::Catch => write!(f, "catch"),
+            TokenType::Finally => write!(f, "finally"),
+            TokenType::Import => write!(f, "import"),
             TokenType::Eof => write!(f, "EOF"),
         }
     }
@@ -106,6 +127,7 @@ pub struct Token {
     pub t_type: TokenType,
     pub line: usize,
     pub col: usize,
+    pub span: Span,
 }
 
 impl fmt::Debug for Token {
@@ -114,6 +136,7 @@ impl fmt::Debug for Token {
             .field("Type", &self.t_type)
             .field("Line", &self.line)
             .field("Col", &self.col)
+            .field("Span", &self.span)
             .finish()
     }
 }
@@ -0,0 +1,863 @@
+// Stack-based virtual machine for the bytecode backend, modelled on clox.
+//
+// The VM shares Value, LoxClass/LoxInstance, the natives and the operator semantics with
+// the tree-walking Interpreter, so both backends print the same output and raise the
+// same RuntimeErrors. Functions compiled to bytecode are wrapped in a Closure, which
+// lives behind Value::Callable like any other callable.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::rc::Rc;
+
+use super::ast::{BiOperator, MapKey, UnOperator, Value};
+use super::chunk::{Function, OpCode};
+use super::compiler::Compiler;
+use super::diagnostic::{Diagnostic, Phase, Span, StackFrame};
+use super::environment::Globals;
+use super::function::{
+    LoxCallable, LoxClass, LoxInstance, LoxMethod, NativeContext, NativeFunction,
+};
+use super::interpreter::{
+    binary, check_arity, ensure_stack, get_index, is_truthy, set_index, standard_globals, unary,
+    Interpreter, RuntimeError, MAX_CALL_DEPTH,
+};
+use super::modules::{self, import_error, Import, ModulesCell};
+use super::output::{OutputCell, Writer};
+
+// A captured variable is open while it still lives on the stack, and gets closed over
+// (moved into the upvalue) once its scope ends
+#[derive(Debug)]
+pub enum UpvalueState {
+    Open(usize),
+    Closed(Value),
+}
+
+pub type UpvalueCell = Rc<RefCell<UpvalueState>>;
+
+#[derive(Debug, Clone)]
+pub struct Closure {
+    function: Rc<Function>,
+    upvalues: Rc<Vec<UpvalueCell>>,
+    // Those of the module the function was defined in
+    globals: Globals,
+}
+
+impl LoxCallable for Closure {
+    // Closures are run by pushing a call frame, see Vm::call_value
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        _args: Vec<Value>,
+    ) -> Result<Value, RuntimeError> {
+        Err(RuntimeError::CallableError {
+            msg: "Bytecode functions can only be called by the VM",
+        })
+    }
+
+    fn arity(&self) -> usize {
+        self.function.arity()
+    }
+
+    fn name(&self) -> Rc<str> {
+        Rc::clone(&self.function.name)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl LoxMethod for Closure {
+    fn bind(&self, instance: Rc<LoxInstance>) -> Rc<dyn LoxCallable> {
+        Rc::new(BoundMethod {
+            receiver: instance,
+            method: self.clone(),
+        })
+    }
+}
+
+impl fmt::Display for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.function)
+    }
+}
+
+#[derive(Debug)]
+pub struct BoundMethod {
+    receiver: Rc<LoxInstance>,
+    method: Closure,
+}
+
+impl LoxCallable for BoundMethod {
+    fn call(&self, interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        self.method.call(interpreter, args)
+    }
+
+    fn arity(&self) -> usize {
+        self.method.arity()
+    }
+
+    fn name(&self) -> Rc<str> {
+        self.method.name()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl fmt::Display for BoundMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.method)
+    }
+}
+
+struct CallFrame {
+    closure: Closure,
+    ip: usize,
+    // Stack index of slot zero for this call
+    base: usize,
+}
+
+impl CallFrame {
+    // Source span of the instruction this frame read last
+    fn current_span(&self) -> Span {
+        self.closure.function.chunk.spans[self.ip - 1]
+    }
+}
+
+// Where an error was raised, and the calls it was raised in
+type Location = (Option<Span>, Vec<StackFrame>);
+
+// Where to carry on when an error is raised inside a `try`
+struct Handler {
+    // The frame that entered the `try`, and how tall the stack was at that point
+    frame_count: usize,
+    stack_len: usize,
+    // Where the catch or finally clause starts in that frame's code
+    target: usize,
+    // A finally clause raises the error again once it's done, instead of catching it
+    finally: bool,
+    // How many errors were held by finally clauses when the `try` was entered
+    held_len: usize,
+}
+
+pub struct Vm {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: Globals,
+    // Looked up when a global isn't defined in the module running
+    builtins: Globals,
+    open_upvalues: Vec<UpvalueCell>,
+    handlers: Vec<Handler>,
+    // Errors waiting for the finally clauses they're running, to be raised again afterwards
+    held: Vec<(RuntimeError, Location)>,
+    // Where the error being raised comes from, when that isn't the current instruction
+    // because a finally clause is raising it again
+    location: Option<Location>,
+    // Whether the bottom frame is a call by the host, rather than the script
+    called_by_host: bool,
+    // How many frames were below the module being imported, which `run` stops at
+    floor: usize,
+    modules: ModulesCell,
+    // The resolver wants an Interpreter to resolve imported modules into, and callables
+    // other than natives and closures are written against one, so the VM keeps one around
+    host: Interpreter,
+    output: OutputCell,
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Vm {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            globals: standard_globals(),
+            builtins: standard_globals(),
+            open_upvalues: Vec::new(),
+            handlers: Vec::new(),
+            held: Vec::new(),
+            location: None,
+            called_by_host: false,
+            floor: 0,
+            modules: Rc::default(),
+            host: Interpreter::new(),
+            output: Rc::new(RefCell::new(Writer(io::stdout()))),
+        }
+    }
+
+    pub fn get_environment(&self) -> String {
+        let output_values: Vec<String> = self
+            .globals
+            .borrow()
+            .iter()
+            .map(|(k, v)| format!("'{}':{}", k, v))
+            .collect();
+        format!("Environment: {{ {} }}", output_values.join("; "))
+    }
+
+    pub fn set_output(&mut self, output: OutputCell) {
+        self.output = output;
+    }
+
+    pub fn set_modules(&mut self, modules: ModulesCell) {
+        self.modules = modules;
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.borrow().get(name).cloned()
+    }
+
+    pub fn define_global(&mut self, name: Rc<str>, value: Value) {
+        self.globals.borrow_mut().insert(name, value);
+    }
+
+    pub fn global_names(&self) -> Vec<Rc<str>> {
+        self.globals.borrow().keys().cloned().collect()
+    }
+
+    // Runs a compiled script, returning the value of a top-level `return` if it has one
+    pub fn interpret(&mut self, function: Function) -> Result<Value, Diagnostic> {
+        let closure = Closure {
+            function: Rc::new(function),
+            upvalues: Rc::new(Vec::new()),
+            globals: Rc::clone(&self.globals),
+        };
+        self.stack.push(Value::Callable(Rc::new(closure.clone())));
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            base: 0,
+        });
+
+        self.called_by_host = false;
+        match self.run() {
+            Ok(()) => Ok(self.pop()),
+            Err(e) => Err(self.abort(e)),
+        }
+    }
+
+    // Calls a function or class on behalf of the host application, running it to completion
+    pub fn call(&mut self, callee: Value, args: Vec<Value>) -> Result<Value, Diagnostic> {
+        let arg_count = args.len();
+        self.called_by_host = true;
+        self.stack.push(callee.clone());
+        self.stack.extend(args);
+
+        // Natives, and classes without an initializer, are done without pushing a frame
+        let result = self.call_value(callee, arg_count).and_then(|()| {
+            if self.frames.is_empty() {
+                Ok(())
+            } else {
+                self.run()
+            }
+        });
+        match result {
+            Ok(()) => Ok(self.pop()),
+            Err(e) => Err(self.abort(e)),
+        }
+    }
+
+    // Reports an error that stopped the VM, where and how deep it was raised, and throws
+    // away the calls it interrupted
+    fn abort(&mut self, error: RuntimeError) -> Diagnostic {
+        let (span, backtrace) = self.location.take().unwrap_or_else(|| self.locate());
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.handlers.clear();
+        self.held.clear();
+        self.floor = 0;
+        Diagnostic::error(Phase::Runtime, error.to_string(), span).with_backtrace(backtrace)
+    }
+
+    // Where the error raised by the current instruction comes from
+    fn locate(&self) -> Location {
+        // The failing instruction is the last one read by the innermost frame
+        let span = self.frames.last().map(|frame| frame.current_span());
+        (span, self.backtrace())
+    }
+
+    // Every frame of a call, innermost first, along with where it was called from.
+    // A caller's last instruction read is always the Call that is still running.
+    // The bottom frame is either the script, which isn't a call, or a call by the host.
+    fn backtrace(&self) -> Vec<StackFrame> {
+        let mut backtrace: Vec<StackFrame> = self
+            .frames
+            .windows(2)
+            .rev()
+            .map(|pair| StackFrame {
+                function: pair[1].closure.function.name.to_string(),
+                call_site: Some(pair[0].current_span()),
+            })
+            .collect();
+
+        if let (true, Some(frame)) = (self.called_by_host, self.frames.first()) {
+            backtrace.push(StackFrame {
+                function: frame.closure.function.name.to_string(),
+                call_site: None,
+            });
+        }
+        backtrace
+    }
+
+    // Errors raised inside a `try` are dealt with here, and execution carries on at its handler
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        loop {
+            match self.execute() {
+                Ok(()) => return Ok(()),
+                Err(error) => self.unwind(error)?,
+            }
+        }
+    }
+
+    // Drops the calls and stack values above the innermost `try`, then resumes at its catch
+    // clause with the error as a value, or at its finally clause holding on to the error
+    fn unwind(&mut self, error: RuntimeError) -> Result<(), RuntimeError> {
+        if !error.is_catchable() {
+            return Err(error);
+        }
+        // A module that is being imported can't catch errors for the code importing it
+        let floor = self.floor;
+        let handler = match self.handlers.pop_if(|handler| handler.frame_count > floor) {
+            Some(handler) => handler,
+            None => return Err(error),
+        };
+        let location = self.location.take().unwrap_or_else(|| self.locate());
+
+        self.held.truncate(handler.held_len);
+        self.close_upvalues(handler.stack_len);
+        self.frames.truncate(handler.frame_count);
+        self.stack.truncate(handler.stack_len);
+        self.frame_mut().ip = handler.target;
+        if handler.finally {
+            self.held.push((error, location));
+        } else {
+            self.stack.push(error.into_value());
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self) -> Result<(), RuntimeError> {
+        loop {
+            let op = OpCode::from_byte(self.read_byte());
+            match op {
+                OpCode::Constant => {
+                    let index = self.read_u16() as usize;
+                    let constant = self.frame().closure.function.chunk.constants[index].clone();
+                    self.stack.push(constant);
+                }
+                OpCode::Nil => self.stack.push(Value::Nil),
+                OpCode::True => self.stack.push(Value::Boolean(true)),
+                OpCode::False => self.stack.push(Value::Boolean(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal => {
+                    let slot = self.frame().base + self.read_u16() as usize;
+                    self.stack.push(self.stack[slot].clone());
+                }
+                OpCode::SetLocal => {
+                    let slot = self.frame().base + self.read_u16() as usize;
+                    self.stack[slot] = self.peek(0).clone();
+                }
+                OpCode::GetGlobal => {
+                    let name = self.read_name();
+                    let value = self.frame().closure.globals.borrow().get(&name).cloned();
+                    match value.or_else(|| self.builtins.borrow().get(&name).cloned()) {
+                        Some(value) => self.stack.push(value),
+                        None => {
+                            return Err(RuntimeError::UndefinedError {
+                                name: name.to_string(),
+                            })
+                        }
+                    }
+                }
+                OpCode::DefineGlobal => {
+                    let name = self.read_name();
+                    let value = self.pop();
+                    self.frame()
+                        .closure
+                        .globals
+                        .borrow_mut()
+                        .insert(name, value);
+                }
+                OpCode::SetGlobal => {
+                    let name = self.read_name();
+                    let value = self.peek(0).clone();
+                    match self.frame().closure.globals.borrow_mut().get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => {
+                            return Err(RuntimeError::UndefinedError {
+                                name: name.to_string(),
+                            })
+                        }
+                    }
+                }
+                OpCode::GetUpvalue => {
+                    let index = self.read_u16() as usize;
+                    let value = match &*self.frame().closure.upvalues[index].borrow() {
+                        UpvalueState::Open(slot) => self.stack[*slot].clone(),
+                        UpvalueState::Closed(value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let index = self.read_u16() as usize;
+                    let value = self.peek(0).clone();
+                    let upvalue = Rc::clone(&self.frame().closure.upvalues[index]);
+                    let mut upvalue = upvalue.borrow_mut();
+                    match &mut *upvalue {
+                        UpvalueState::Open(slot) => self.stack[*slot] = value,
+                        UpvalueState::Closed(closed) => *closed = value,
+                    }
+                }
+                OpCode::GetProperty => {
+                    let name = self.read_name();
+                    match self.pop() {
+                        Value::Instance(instance) => {
+                            let value = instance.get(&name)?;
+                            self.stack.push(value);
+                        }
+                        object => {
+                            return Err(RuntimeError::type_error(
+                                &object,
+                                "Only instances have properties.",
+                            ))
+                        }
+                    }
+                }
+                OpCode::SetProperty => {
+                    let name = self.read_name();
+                    let value = self.pop();
+                    match self.pop() {
+                        Value::Instance(instance) => {
+                            instance.set(name, value.clone());
+                            self.stack.push(value);
+                        }
+                        object => {
+                            return Err(RuntimeError::type_error(
+                                &object,
+                                "Only instances have fields.",
+                            ))
+                        }
+                    }
+                }
+                OpCode::GetSuper => {
+                    let name = self.read_name();
+                    let superclass = match self.pop() {
+                        Value::Class(superclass) => superclass,
+                        _ => unreachable!(),
+                    };
+                    let instance = match self.pop() {
+                        Value::Instance(instance) => instance,
+                        _ => unreachable!(),
+                    };
+                    match superclass.find_method(&name) {
+                        Some(method) => self.stack.push(Value::Callable(method.bind(instance))),
+                        None => {
+                            return Err(RuntimeError::PropertyError {
+                                name: name.to_string(),
+                            })
+                        }
+                    }
+                }
+                OpCode::GetIndex => {
+                    let index = self.pop();
+                    let object = self.pop();
+                    self.stack.push(get_index(&object, &index)?);
+                }
+                OpCode::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let object = self.pop();
+                    set_index(&object, &index, value.clone())?;
+                    self.stack.push(value);
+                }
+                OpCode::Equal => self.binary_op(BiOperator::Eq)?,
+                OpCode::NotEqual => self.binary_op(BiOperator::NotEq)?,
+                OpCode::Greater => self.binary_op(BiOperator::Greater)?,
+                OpCode::GreaterEqual => self.binary_op(BiOperator::GreaterEqual)?,
+                OpCode::Less => self.binary_op(BiOperator::Less)?,
+                OpCode::LessEqual => self.binary_op(BiOperator::LessEqual)?,
+                OpCode::Add => self.binary_op(BiOperator::Plus)?,
+                OpCode::Subtract => self.binary_op(BiOperator::Minus)?,
+                OpCode::Multiply => self.binary_op(BiOperator::Star)?,
+                OpCode::Divide => self.binary_op(BiOperator::Slash)?,
+                OpCode::Not => {
+                    let right = self.pop();
+                    self.stack.push(unary(&UnOperator::Bang, &right)?);
+                }
+                OpCode::Negate => {
+                    let right = self.pop();
+                    self.stack.push(unary(&UnOperator::Minus, &right)?);
+                }
+                OpCode::Stringify => {
+                    let right = self.pop();
+                    self.stack.push(unary(&UnOperator::Stringify, &right)?);
+                }
+                OpCode::Print => {
+                    let value = self.pop();
+                    self.output.borrow_mut().print(&value.to_string());
+                }
+                OpCode::Jump => {
+                    let offset = self.read_u32() as usize;
+                    self.frame_mut().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_u32() as usize;
+                    if !is_truthy(self.peek(0)) {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_u32() as usize;
+                    self.frame_mut().ip -= offset;
+                }
+                OpCode::Throw => return Err(RuntimeError::Thrown(self.pop())),
+                OpCode::Try | OpCode::TryFinally => {
+                    let offset = self.read_u32() as usize;
+                    self.handlers.push(Handler {
+                        frame_count: self.frames.len(),
+                        stack_len: self.stack.len(),
+                        target: self.frame().ip + offset,
+                        finally: op == OpCode::TryFinally,
+                        held_len: self.held.len(),
+                    });
+                }
+                OpCode::EndTry => {
+                    self.handlers.pop();
+                }
+                OpCode::Rethrow => {
+                    let (error, location) = self.held.pop().unwrap();
+                    self.location = Some(location);
+                    return Err(error);
+                }
+                OpCode::DropError => {
+                    self.held.pop();
+                }
+                OpCode::Import => {
+                    let path = self.read_name();
+                    let namespace = self.import(&path)?;
+                    self.stack.push(namespace);
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte() as usize;
+                    let callee = self.peek(arg_count).clone();
+                    self.call_value(callee, arg_count)?;
+                }
+                OpCode::Closure => {
+                    let index = self.read_u16() as usize;
+                    let function = Rc::clone(&self.frame().closure.function.chunk.functions[index]);
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count);
+                    for _ in 0..function.upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_u16() as usize;
+                        if is_local {
+                            let slot = self.frame().base + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            upvalues.push(Rc::clone(&self.frame().closure.upvalues[index]));
+                        }
+                    }
+                    let closure = Closure {
+                        function,
+                        upvalues: Rc::new(upvalues),
+                        globals: Rc::clone(&self.frame().closure.globals),
+                    };
+                    self.stack.push(Value::Callable(Rc::new(closure)));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.base);
+                    self.stack.truncate(frame.base);
+                    self.stack.push(result);
+                    // The result of the outermost call is left for `interpret`, `call` or
+                    // the import that ran a module
+                    if self.frames.len() == self.floor {
+                        return Ok(());
+                    }
+                }
+                OpCode::Class => {
+                    let name = self.read_name();
+                    let has_superclass = self.read_byte() == 1;
+                    let method_count = self.read_u16() as usize;
+
+                    let mut methods: HashMap<Rc<str>, Rc<dyn LoxMethod>> = HashMap::new();
+                    for value in self.stack.split_off(self.stack.len() - method_count) {
+                        if let Value::Callable(callable) = value {
+                            if let Some(closure) = callable.as_any().downcast_ref::<Closure>() {
+                                methods.insert(closure.name(), Rc::new(closure.clone()));
+                            }
+                        }
+                    }
+
+                    let superclass = if has_superclass {
+                        match self.pop() {
+                            Value::Class(superclass) => Some(superclass),
+                            val => {
+                                return Err(RuntimeError::type_error(
+                                    &val,
+                                    "Superclass must be a class.",
+                                ))
+                            }
+                        }
+                    } else {
+                        None
+                    };
+
+                    let class = LoxClass::new(name, superclass, methods);
+                    self.stack.push(Value::Class(Rc::new(class)));
+                }
+                OpCode::BuildList => {
+                    let count = self.read_u16() as usize;
+                    let elements = self.stack.split_off(self.stack.len() - count);
+                    self.stack
+                        .push(Value::List(Rc::new(RefCell::new(elements))));
+                }
+                OpCode::BuildMap => {
+                    let count = self.read_u16() as usize;
+                    let entries = self.stack.split_off(self.stack.len() - count * 2);
+                    let mut map = HashMap::with_capacity(count);
+                    for entry in entries.chunks(2) {
+                        map.insert(MapKey::from_value(&entry[0])?, entry[1].clone());
+                    }
+                    self.stack.push(Value::Map(Rc::new(RefCell::new(map))));
+                }
+            }
+        }
+    }
+
+    // The callee sits on the stack just below its arguments, and becomes slot zero
+    // of the new frame (or is replaced by the receiver for methods and initializers)
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), RuntimeError> {
+        let base = self.stack.len() - arg_count - 1;
+        match callee {
+            Value::Callable(callable) => {
+                if let Some(closure) = callable.as_any().downcast_ref::<Closure>() {
+                    self.call_closure(closure.clone(), arg_count)
+                } else if let Some(bound) = callable.as_any().downcast_ref::<BoundMethod>() {
+                    self.stack[base] = Value::Instance(Rc::clone(&bound.receiver));
+                    self.call_closure(bound.method.clone(), arg_count)
+                } else if let Some(native) = callable.as_any().downcast_ref::<NativeFunction>() {
+                    check_arity(native.arity(), arg_count)?;
+                    let args = self.stack.split_off(base + 1);
+                    self.pop();
+                    let result = native.invoke(self, args);
+                    // Where a callback's error came from is only wanted if the native let it through
+                    if result.is_ok() {
+                        self.location = None;
+                    }
+                    self.stack.push(result?);
+                    Ok(())
+                } else {
+                    check_arity(callable.arity(), arg_count)?;
+                    let args = self.stack.split_off(base + 1);
+                    self.pop();
+                    let result = callable.call(&mut self.host, args)?;
+                    self.stack.push(result);
+                    Ok(())
+                }
+            }
+            Value::Class(class) => {
+                check_arity(class.arity(), arg_count)?;
+                let instance = Rc::new(LoxInstance::new(Rc::clone(&class)));
+                self.stack[base] = Value::Instance(instance);
+                if let Some(initializer) = class.find_method("init") {
+                    if let Some(closure) = initializer.as_any().downcast_ref::<Closure>() {
+                        return self.call_closure(closure.clone(), arg_count);
+                    }
+                }
+                Ok(())
+            }
+            _ => Err(RuntimeError::CallableError {
+                msg: "Can only call functions and classes",
+            }),
+        }
+    }
+
+    // Runs the module at `path` the first time it's imported, and hands back its namespace
+    fn import(&mut self, path: &str) -> Result<Value, RuntimeError> {
+        let modules = Rc::clone(&self.modules);
+        let import = modules.borrow_mut().begin(path)?;
+        let (name, source) = match import {
+            Import::Cached(namespace) => return Ok(namespace),
+            Import::Load { name, source } => (name, source),
+        };
+        let result = self.run_module(path, source);
+        modules
+            .borrow_mut()
+            .finish(&name, result.as_ref().ok().cloned());
+        result
+    }
+
+    // A module runs like a call in a global scope of its own, but to the end before the
+    // import carries on, so that it can be cached. An error it raises is left with its frames
+    // still in place, to be located and handled like any other.
+    fn run_module(&mut self, path: &str, source: String) -> Result<Value, RuntimeError> {
+        let statements = modules::parse(source, &mut self.host)
+            .map_err(|d| import_error(path, d.to_string()))?;
+        let mut function = Compiler::new()
+            .compile(&statements)
+            .map_err(|e| import_error(path, e.to_string()))?;
+        function.name = Rc::from(path);
+
+        let globals = Globals::default();
+        let closure = Closure {
+            function: Rc::new(function),
+            upvalues: Rc::new(Vec::new()),
+            globals: Rc::clone(&globals),
+        };
+        self.stack.push(Value::Callable(Rc::new(closure.clone())));
+        let floor = mem::replace(&mut self.floor, self.frames.len());
+        let result = self.call_closure(closure, 0).and_then(|()| self.run());
+        self.floor = floor;
+
+        result?;
+        // What's left is the value of a top-level `return`, which imports ignore
+        self.pop();
+        Ok(modules::namespace(globals))
+    }
+
+    fn call_closure(&mut self, closure: Closure, arg_count: usize) -> Result<(), RuntimeError> {
+        check_arity(closure.function.arity(), arg_count)?;
+        // The script's own frame doesn't count as a call
+        if self.frames.len() > MAX_CALL_DEPTH {
+            return Err(RuntimeError::StackOverflow);
+        }
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            base: self.stack.len() - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    // Reuses an existing upvalue for the slot, so closures share captured variables
+    fn capture_upvalue(&mut self, slot: usize) -> UpvalueCell {
+        for upvalue in self.open_upvalues.iter() {
+            if let UpvalueState::Open(open_slot) = *upvalue.borrow() {
+                if open_slot == slot {
+                    return Rc::clone(upvalue);
+                }
+            }
+        }
+        let upvalue = Rc::new(RefCell::new(UpvalueState::Open(slot)));
+        self.open_upvalues.push(Rc::clone(&upvalue));
+        upvalue
+    }
+
+    // Moves every captured variable at or above `from` off the stack
+    fn close_upvalues(&mut self, from: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+            match *upvalue {
+                UpvalueState::Open(slot) if slot >= from => {
+                    *upvalue = UpvalueState::Closed(stack[slot].clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    fn binary_op(&mut self, op: BiOperator) -> Result<(), RuntimeError> {
+        let right = self.pop();
+        let left = self.pop();
+        self.stack.push(binary(&op, &left, &right)?);
+        Ok(())
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte = frame.closure.function.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let frame = self.frame_mut();
+        let value = frame.closure.function.chunk.read_u16(frame.ip);
+        frame.ip += 2;
+        value
+    }
+
+    fn read_u32(&mut self) -> u32 {
+        let frame = self.frame_mut();
+        let value = frame.closure.function.chunk.read_u32(frame.ip);
+        frame.ip += 4;
+        value
+    }
+
+    fn read_name(&mut self) -> Rc<str> {
+        let index = self.read_u16() as usize;
+        Rc::clone(&self.frame().closure.function.chunk.names[index])
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("Stack underflow.")
+    }
+
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+}
+
+// A native calling back into Lox code runs the call to completion before carrying on, on
+// top of the frames that are waiting for the native to return. Like an import, the call
+// can't catch errors for the code below it. An error is located before its frames are
+// thrown away, since the native may well let it through.
+impl NativeContext for Vm {
+    fn call(&mut self, callee: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        let (frame_count, stack_len, held_len) =
+            (self.frames.len(), self.stack.len(), self.held.len());
+        let arg_count = args.len();
+        self.stack.push(callee.clone());
+        self.stack.extend(args);
+
+        let floor = mem::replace(&mut self.floor, frame_count);
+        let result = self.call_value(callee, arg_count).and_then(|()| {
+            if self.frames.len() == self.floor {
+                Ok(())
+            } else {
+                ensure_stack(|| self.run())
+            }
+        });
+        self.floor = floor;
+
+        if let Err(error) = result {
+            if self.location.is_none() {
+                self.location = Some(self.locate());
+            }
+            self.close_upvalues(stack_len);
+            self.frames.truncate(frame_count);
+            self.stack.truncate(stack_len);
+            self.held.truncate(held_len);
+            return Err(error);
+        }
+        Ok(self.pop())
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
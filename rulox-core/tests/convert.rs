@@ -0,0 +1,21 @@
+use rulox_core::rulox::convert::{from_value, to_value};
+use rulox_core::rulox::session::Error;
+
+#[test]
+fn integers_round_trip() {
+    let max = 1u64 << 53;
+    assert_eq!(from_value::<u64>(to_value(&max).unwrap()), Ok(max));
+    let min = -(1i64 << 53);
+    assert_eq!(from_value::<i64>(to_value(&min).unwrap()), Ok(min));
+}
+
+#[test]
+fn integers_that_a_double_would_round_are_refused() {
+    for result in [
+        to_value(&u64::MAX),
+        to_value(&((1u64 << 53) + 1)),
+        to_value(&i64::MIN),
+    ] {
+        assert!(matches!(result, Err(Error::Convert(_))), "{:?}", result);
+    }
+}
@@ -0,0 +1,27 @@
+use std::collections::HashMap;
+
+use rulox_core::rulox::ast::{MapKey, Value};
+
+#[test]
+fn list_containing_itself() {
+    let list = Value::from(vec![1.0]);
+    if let Value::List(elements) = &list {
+        elements.borrow_mut().push(list.clone());
+    }
+    assert_eq!(list.to_string(), "[1, [...]]");
+    // Only a list inside itself is cut short, not one that shows up twice side by side
+    assert_eq!(
+        Value::from(vec![list.clone(), list]).to_string(),
+        "[[1, [...]], [1, [...]]]"
+    );
+}
+
+#[test]
+fn map_containing_itself() {
+    let map = Value::from(HashMap::<String, Value>::new());
+    if let Value::Map(entries) = &map {
+        let key = MapKey::String(Box::new(String::from("self")));
+        entries.borrow_mut().insert(key, map.clone());
+    }
+    assert_eq!(map.to_string(), "{\"self\": {...}}");
+}
@@ -0,0 +1,54 @@
+use rulox_core::rulox::modules::MemoryLoader;
+use rulox_core::rulox::{Backend, Rulox};
+
+// Runs `source` on both backends with `modules` importable, checking they print the same
+fn run(modules: &[(&str, &str)], source: &str) -> Vec<String> {
+    let outputs: Vec<Vec<String>> = [Backend::TreeWalk, Backend::Bytecode]
+        .iter()
+        .map(|backend| {
+            let mut loader = MemoryLoader::new();
+            for (path, module) in modules {
+                loader.add(path, module.to_string());
+            }
+            let mut rulox = Rulox::new(source.to_owned());
+            rulox.set_backend(*backend);
+            rulox.set_loader(loader);
+            rulox.tokenize();
+            rulox.parse();
+            rulox.resolve();
+            rulox.run()
+        })
+        .collect();
+    assert_eq!(outputs[0], outputs[1]);
+    outputs[0].clone()
+}
+
+const COUNTER: &str = "var count = 0; fun inc() { count = count + 1; return count; }";
+
+#[test]
+fn namespace_reads_the_module_globals() {
+    let output = run(
+        &[("counter.lox", COUNTER)],
+        "import m from \"counter.lox\"; m.inc(); m.inc(); print m.count;",
+    );
+    assert_eq!(output, ["2"]);
+}
+
+#[test]
+fn namespace_writes_the_module_globals() {
+    let output = run(
+        &[("counter.lox", COUNTER)],
+        "import m from \"counter.lox\"; m.count = 10; print m.inc();",
+    );
+    assert_eq!(output, ["11"]);
+}
+
+#[test]
+fn namespace_leaves_out_the_standard_library() {
+    let output = run(
+        &[("timer.lox", "fun positive() { return clock() > 0; }")],
+        "import t from \"timer.lox\"; print t.positive();
+         try { t.clock; } catch (e) { print e.kind; }",
+    );
+    assert_eq!(output, ["true", "\"PropertyError\""]);
+}
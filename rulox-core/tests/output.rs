@@ -0,0 +1,43 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use rulox_core::rulox::ast::Value;
+use rulox_core::rulox::session::Session;
+use rulox_core::rulox::{Backend, Rulox};
+
+#[test]
+fn closure_receives_lines_as_they_are_printed() {
+    for backend in [Backend::TreeWalk, Backend::Bytecode] {
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let mut session = Session::with_backend(backend);
+        let sink = Rc::clone(&lines);
+        session.set_output(move |line: &str| sink.borrow_mut().push(line.to_owned()));
+        // Tells the script how many lines the output has received so far
+        let seen = Rc::clone(&lines);
+        session.register_native("seen", 0, move |_, _| {
+            Ok(Value::Number(seen.borrow().len() as f64))
+        });
+
+        session
+            .eval("print \"first\"; print seen(); print seen();")
+            .unwrap();
+        assert_eq!(*lines.borrow(), ["\"first\"", "1", "2"], "{:?}", backend);
+    }
+}
+
+#[test]
+fn run_returns_nothing_once_output_is_set() {
+    for backend in [Backend::TreeWalk, Backend::Bytecode] {
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let mut rulox = Rulox::new(String::from("print 1; print 2;"));
+        rulox.set_backend(backend);
+        let sink = Rc::clone(&lines);
+        rulox.set_output(move |line: &str| sink.borrow_mut().push(line.to_owned()));
+        rulox.tokenize();
+        rulox.parse();
+        rulox.resolve();
+
+        assert!(rulox.run().is_empty(), "{:?}", backend);
+        assert_eq!(*lines.borrow(), ["1", "2"], "{:?}", backend);
+    }
+}
@@ -0,0 +1,71 @@
+use std::fs;
+use std::path::Path;
+
+use rulox_core::rulox::{Backend, Rulox};
+
+// What a script printed, and the diagnostics it raised
+fn run(source: &str, backend: Backend) -> (Vec<String>, Vec<String>) {
+    let mut rulox = Rulox::new(source.to_owned());
+    rulox.set_backend(backend);
+    rulox.tokenize();
+    rulox.parse();
+    rulox.resolve();
+    let output = rulox.run();
+    let diagnostics = rulox.diagnostics().iter().map(|d| d.to_string()).collect();
+    (output, diagnostics)
+}
+
+fn assert_same_on_both_backends(name: &str, source: &str) -> Vec<String> {
+    let tree_walk = run(source, Backend::TreeWalk);
+    assert_eq!(tree_walk, run(source, Backend::Bytecode), "{}", name);
+    tree_walk.0
+}
+
+// Every script in tests/parity prints the same and stops with the same error on either backend
+#[test]
+fn scripts_behave_the_same_on_both_backends() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/parity");
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let source = fs::read_to_string(&path).unwrap();
+        assert_same_on_both_backends(&path.display().to_string(), &source);
+    }
+}
+
+#[test]
+fn class_with_many_methods() {
+    let methods: String = (0..300)
+        .map(|i| format!("m{}() {{ return {}; }}\n", i, i))
+        .collect();
+    let source = format!(
+        "class A {{\n{}}}\nprint A().m5();\nprint A().m299();",
+        methods
+    );
+    let output = assert_same_on_both_backends("many methods", &source);
+    assert_eq!(output, ["5", "299"]);
+}
+
+#[test]
+fn function_with_many_locals() {
+    let locals: String = (0..300).map(|i| format!("var v{} = {};\n", i, i)).collect();
+    // The closure captures every one of them as well
+    let sum: String = (0..300).map(|i| format!("s = s + v{};\n", i)).collect();
+    let source = format!(
+        "fun f() {{\n{}fun g() {{\nvar s = 0;\n{}return s;\n}}\nreturn g();\n}}\nprint f();",
+        locals, sum
+    );
+    let output = assert_same_on_both_backends("many locals", &source);
+    assert_eq!(output, ["44850"]);
+}
+
+#[test]
+fn body_too_long_for_a_short_jump() {
+    let body = "n = n + 1;\n".repeat(9000);
+    let source = format!(
+        "var n = 0;\nif (true) {{\n{0}}}\nwhile (n < 18000) {{\n{0}}}\n\
+         try {{\n{0}throw n;\n}} catch (e) {{ print e; }}",
+        body
+    );
+    let output = assert_same_on_both_backends("long body", &source);
+    assert_eq!(output, ["27000"]);
+}
@@ -0,0 +1,63 @@
+use rulox_core::rulox::diagnostic::Phase;
+use rulox_core::rulox::Rulox;
+
+fn parse(source: &str) -> Rulox {
+    let mut rulox = Rulox::new(source.to_owned());
+    rulox.tokenize();
+    rulox.parse();
+    rulox
+}
+
+#[test]
+fn expression_cut_off_by_the_end_of_input() {
+    let sources = [
+        "print [",
+        "print {",
+        "x[",
+        "print (",
+        "f(",
+        "print [1,",
+        "print {1:",
+        "print -",
+    ];
+    for source in sources {
+        let rulox = parse(source);
+        assert!(rulox.had_errors, "{}", source);
+        let diagnostic = &rulox.diagnostics()[0];
+        assert_eq!(diagnostic.phase, Phase::Parse, "{}", source);
+        assert_eq!(diagnostic.message, "Expect expression.", "{}", source);
+    }
+}
+
+#[test]
+fn unterminated_interpolation() {
+    // The parser still goes on to the end of input, where the expression is cut off
+    let cases = [
+        (
+            "print \"${\";",
+            [
+                "[Scan error @ 1:10] Unterminated string.",
+                "[Parse error @ 1:12] Expect expression.",
+            ],
+        ),
+        (
+            "print \"${x\";",
+            [
+                "[Scan error @ 1:11] Unterminated string.",
+                "[Parse error @ 1:10] Expect '}' after interpolation.",
+            ],
+        ),
+        (
+            "print \"a${\"b${\"c",
+            [
+                "[Scan error @ 1:15] Unterminated string.",
+                "[Parse error @ 1:17] Expect expression.",
+            ],
+        ),
+    ];
+    for (source, expected) in cases {
+        let rulox = parse(source);
+        let diagnostics: Vec<String> = rulox.diagnostics().iter().map(|d| d.to_string()).collect();
+        assert_eq!(diagnostics, expected, "{}", source);
+    }
+}
@@ -0,0 +1,55 @@
+use rulox_core::rulox::diagnostic::Phase;
+use rulox_core::rulox::{Backend, Rulox};
+
+// Takes the loaded source through every stage, returning what it printed
+fn run(rulox: &mut Rulox) -> Vec<String> {
+    rulox.tokenize();
+    rulox.parse();
+    rulox.resolve();
+    rulox.run()
+}
+
+#[test]
+fn resolver_errors_stop_the_program() {
+    let sources = [
+        "print 1; return 1;",
+        "print 1; break;",
+        "print 1; continue;",
+        "print 1; print this;",
+        "print 1; super.x;",
+        "print 1; fun f() { super.x; } f();",
+        "print 1; class A { m() { return super.m; } } A().m();",
+        "print 1; { var a = 1; var a = 2; }",
+    ];
+    for source in sources {
+        let mut rulox = Rulox::new(source.to_owned());
+        let output = run(&mut rulox);
+        assert!(rulox.had_errors, "{}", source);
+        assert!(output.is_empty(), "{} printed {:?}", source, output);
+        let diagnostic = &rulox.diagnostics()[0];
+        assert!(diagnostic.is_error());
+        assert_eq!(diagnostic.phase, Phase::Resolve);
+    }
+}
+
+// Hosts can still run a parse tree the resolver hasn't seen, which must not crash either backend
+#[test]
+fn unresolved_super_is_a_runtime_error() {
+    let sources = [
+        "super.x;",
+        "fun f() { super.x; } f();",
+        "class A { m() { return super.m; } } A().m();",
+    ];
+    for source in sources {
+        for backend in [Backend::TreeWalk, Backend::Bytecode] {
+            let mut rulox = Rulox::new(source.to_owned());
+            rulox.set_backend(backend);
+            rulox.tokenize();
+            rulox.parse();
+            rulox.run();
+            let diagnostic = &rulox.diagnostics()[0];
+            assert_eq!(diagnostic.phase, Phase::Runtime, "{}", source);
+            assert!(diagnostic.message.starts_with("Undefined variable"));
+        }
+    }
+}
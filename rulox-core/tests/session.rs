@@ -0,0 +1,253 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use rulox_core::rulox::ast::Value;
+use rulox_core::rulox::interpreter::RuntimeError;
+use rulox_core::rulox::session::{Error, FromValue, Session};
+use rulox_core::rulox::Backend;
+
+const BACKENDS: [Backend; 2] = [Backend::TreeWalk, Backend::Bytecode];
+
+// A session printing into the returned list instead of stdout
+fn session(backend: Backend) -> (Session, Rc<RefCell<Vec<String>>>) {
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let mut session = Session::with_backend(backend);
+    let sink = Rc::clone(&lines);
+    session.set_output(move |line: &str| sink.borrow_mut().push(line.to_owned()));
+    (session, lines)
+}
+
+// Calls its first argument with the second, from Rust
+fn register_apply(session: &mut Session) {
+    session.register_native("apply", 2, |context, mut args| {
+        let arg = args.pop().unwrap();
+        let callee = args.pop().unwrap();
+        context.call(callee, vec![arg])
+    });
+}
+
+fn number(value: Value) -> f64 {
+    f64::from_value(value).unwrap()
+}
+
+#[test]
+fn eval_returns_a_trailing_expression() {
+    for backend in BACKENDS {
+        let (mut session, lines) = session(backend);
+        session.eval("var x = 2; print x;").unwrap();
+        assert_eq!(
+            number(session.eval("x * 3;").unwrap()),
+            6.0,
+            "{:?}",
+            backend
+        );
+        assert_eq!(
+            session.eval("print x;").unwrap(),
+            Value::Nil,
+            "{:?}",
+            backend
+        );
+        assert_eq!(*lines.borrow(), ["2", "2"], "{:?}", backend);
+    }
+}
+
+#[test]
+fn eval_reports_errors() {
+    for backend in BACKENDS {
+        let (mut session, _) = session(backend);
+        assert!(
+            matches!(session.eval("print ;"), Err(Error::Compile(_))),
+            "{:?}",
+            backend
+        );
+        match session.eval("fun f() { return 1 + nil; }\nf();") {
+            Err(Error::Runtime(diagnostic)) => assert_eq!(
+                diagnostic.to_string(),
+                "[Runtime error @ 1:18] Found nil; Expected number\n    in f() called at 2:1",
+                "{:?}",
+                backend
+            ),
+            result => panic!("{:?}: {:?}", backend, result),
+        }
+        // The session carries on after either
+        assert_eq!(
+            number(session.eval("1 + 1;").unwrap()),
+            2.0,
+            "{:?}",
+            backend
+        );
+    }
+}
+
+#[test]
+fn globals_can_be_read_and_written() {
+    for backend in BACKENDS {
+        let (mut session, _) = session(backend);
+        session.set("name", "lox");
+        session.set("count", 1.0);
+        session.eval("count = count + len(name);").unwrap();
+        assert_eq!(session.get::<f64>("count"), Ok(4.0), "{:?}", backend);
+        assert_eq!(
+            session.get::<String>("name"),
+            Ok(String::from("lox")),
+            "{:?}",
+            backend
+        );
+        assert_eq!(
+            session.get::<f64>("missing"),
+            Err(Error::Undefined(String::from("missing"))),
+            "{:?}",
+            backend
+        );
+        assert!(
+            matches!(session.get::<bool>("count"), Err(Error::Type { .. })),
+            "{:?}",
+            backend
+        );
+    }
+}
+
+#[test]
+fn functions_and_classes_can_be_called() {
+    for backend in BACKENDS {
+        let (mut session, _) = session(backend);
+        session
+            .eval("fun area(w, h) { return w * h; }\nclass P { init(x) { this.x = x; } }")
+            .unwrap();
+        let area = session.call("area", vec![4.0.into(), 5.0.into()]).unwrap();
+        assert_eq!(number(area), 20.0, "{:?}", backend);
+        let point = session.call("P", vec![3.0.into()]).unwrap();
+        session.set("p", point);
+        assert_eq!(number(session.eval("p.x;").unwrap()), 3.0, "{:?}", backend);
+
+        let lambda = session.eval("fun (n) { return n + 1; };").unwrap();
+        let result = session.call_value(lambda, vec![1.0.into()]).unwrap();
+        assert_eq!(number(result), 2.0, "{:?}", backend);
+    }
+}
+
+#[test]
+fn natives_can_call_back_into_lox() {
+    for backend in BACKENDS {
+        let (mut session, lines) = session(backend);
+        register_apply(&mut session);
+        session.register_native("double", 1, |_, args| match &args[0] {
+            Value::Number(n) => Ok(Value::Number(n * 2.0)),
+            val => Err(RuntimeError::type_error(val, "Expected number.")),
+        });
+
+        let result = session
+            .eval("apply(fun (x) { return x + 1; }, 1);")
+            .unwrap();
+        assert_eq!(number(result), 2.0, "{:?}", backend);
+        // Callbacks can be natives, classes and closures over locals still on the stack
+        session
+            .eval(
+                "print apply(double, 4);\n\
+                 class A { init(x) { this.x = x; } }\n\
+                 print apply(A, 5).x;\n\
+                 fun count() { var n = 0; apply(fun (d) { n = n + d; }, 3); return n; }\n\
+                 print count();",
+            )
+            .unwrap();
+        assert_eq!(*lines.borrow(), ["8", "5", "3"], "{:?}", backend);
+    }
+}
+
+#[test]
+fn errors_in_callbacks_reach_the_script() {
+    for backend in BACKENDS {
+        let (mut session, lines) = session(backend);
+        register_apply(&mut session);
+        session
+            .eval(
+                "fun fail(x) { throw x; }\n\
+                 try { apply(fail, \"caught\"); } catch (e) { print e; }\n\
+                 print apply(fun (x) { try { fail(x); } catch (e) { return e + \"!\"; } }, \"inner\");",
+            )
+            .unwrap();
+        assert_eq!(
+            *lines.borrow(),
+            ["\"caught\"", "\"inner!\""],
+            "{:?}",
+            backend
+        );
+
+        match session.eval("fun g(n) { return n + nil; }\napply(g, 1);") {
+            Err(Error::Runtime(diagnostic)) => assert_eq!(
+                diagnostic.to_string(),
+                "[Runtime error @ 1:19] Found nil; Expected number\n    in g() called at 2:1",
+                "{:?}",
+                backend
+            ),
+            result => panic!("{:?}: {:?}", backend, result),
+        }
+    }
+}
+
+// A native may also ignore a callback's error and carry on
+#[test]
+fn natives_can_swallow_callback_errors() {
+    for backend in BACKENDS {
+        let (mut session, _) = session(backend);
+        session.register_native("attempt", 1, |context, mut args| {
+            let callee = args.pop().unwrap();
+            Ok(Value::Boolean(context.call(callee, Vec::new()).is_ok()))
+        });
+        let result = session
+            .eval("fun f() { return nil + 1; }\nvar ok = attempt(f);\nvar b = 2;\n[ok, b];")
+            .unwrap();
+        assert_eq!(
+            Vec::<Value>::from_value(result),
+            Ok(vec![Value::Boolean(false), Value::Number(2.0)]),
+            "{:?}",
+            backend
+        );
+        // Rather than where the swallowed error was raised
+        match session.eval("\nnil + 1;") {
+            Err(Error::Runtime(diagnostic)) => assert_eq!(
+                diagnostic.to_string(),
+                "[Runtime error @ 2:1] Found nil; Expected number or string",
+                "{:?}",
+                backend
+            ),
+            result => panic!("{:?}: {:?}", backend, result),
+        }
+    }
+}
+
+// Test threads have a small stack, far less than a thousand tree-walker calls need
+#[test]
+fn runaway_recursion_is_a_stack_overflow_error() {
+    for backend in BACKENDS {
+        let (mut session, _) = session(backend);
+        match session.eval("fun r() { r(); } r();") {
+            Err(Error::Runtime(diagnostic)) => {
+                assert!(
+                    diagnostic.message.starts_with("Stack overflow"),
+                    "{:?}",
+                    backend
+                )
+            }
+            result => panic!("{:?}: {:?}", backend, result),
+        }
+    }
+}
+
+#[test]
+fn runaway_recursion_through_a_native_is_a_stack_overflow_error() {
+    for backend in BACKENDS {
+        let (mut session, _) = session(backend);
+        register_apply(&mut session);
+        match session.eval("fun r(n) { apply(r, n); } r(0);") {
+            Err(Error::Runtime(diagnostic)) => {
+                assert!(
+                    diagnostic.message.starts_with("Stack overflow"),
+                    "{:?}",
+                    backend
+                )
+            }
+            result => panic!("{:?}: {:?}", backend, result),
+        }
+    }
+}
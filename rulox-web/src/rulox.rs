@@ -1,8 +1,18 @@
 use rulox_core::rulox::{
-    ast::Stmt, interpreter::Interpreter, parser::Parser, resolver::Resolver, scanner::Scanner,
-    token::Token, Rulox,
+    ast::Stmt,
+    diagnostic::Diagnostic,
+    interpreter::Interpreter,
+    modules::{MemoryLoader, Modules},
+    output::Buffer,
+    parser::Parser,
+    resolver::Resolver,
+    scanner::Scanner,
+    token::Token,
+    Rulox,
 };
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen]
@@ -12,7 +22,11 @@ pub struct WebRulox {
     environments: String,
     parse_tree: Vec<Stmt>,
     token_stream: Vec<Token>,
-    error_msg: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+    // JS function called with each line the program prints, as it's printed
+    output: Option<js_sys::Function>,
+    // There are no files to import from in the browser, only modules added with `add_module`
+    modules: MemoryLoader,
 }
 
 #[wasm_bindgen]
@@ -24,31 +38,48 @@ impl WebRulox {
             environments: String::new(),
             token_stream: Vec::new(),
             parse_tree: Vec::new(),
-            error_msg: Vec::new(),
+            diagnostics: Vec::new(),
+            output: None,
+            modules: MemoryLoader::new(),
         }
     }
 
+    // Diagnostics are also rendered into the output, so the result area shows them
     fn run(&mut self) -> Vec<String> {
+        if self.had_errors {
+            return self.diagnostics.iter().map(|d| d.to_string()).collect();
+        }
+
         let mut interpreter = Interpreter::new();
+        let buffer = Rc::new(RefCell::new(Buffer::default()));
+        match &self.output {
+            Some(callback) => {
+                let callback = callback.clone();
+                interpreter.set_output(Rc::new(RefCell::new(move |line: &str| {
+                    let _ = callback.call1(&JsValue::NULL, &JsValue::from_str(line));
+                })));
+            }
+            None => interpreter.set_output(buffer.clone()),
+        }
+        let modules = Modules::new(self.modules.clone());
+        interpreter.set_modules(Rc::new(RefCell::new(modules)));
 
         let mut resolver = Resolver::new(&mut interpreter);
+        let mut diagnostics = resolver.resolve_source(&self.parse_tree);
 
-        let mut output = Vec::new();
-        match resolver.resolve_source(&self.parse_tree) {
-            Err(e) => output.push(format!("Resolver error: {:?}", e)),
-            _ => {}
-        }
-
-        if self.had_errors {
-            output.append(&mut self.error_msg);
-            return output;
+        // A program with resolver errors isn't run, like one with syntax errors
+        if !diagnostics.iter().any(|d| d.is_error()) {
+            if let Err(diagnostic) = interpreter.interpret(self.parse_tree.clone()) {
+                diagnostics.push(diagnostic);
+            }
         }
-
-        let mut result = interpreter.interpret(self.parse_tree.clone());
-        output.append(&mut result);
         self.environments = interpreter.get_environment();
 
-        if output.len() == 0 {
+        let mut output = buffer.borrow_mut().take();
+        output.extend(diagnostics.iter().map(|d| d.to_string()));
+        self.diagnostics.append(&mut diagnostics);
+
+        if output.len() == 0 && self.output.is_none() {
             output.push(String::from("No output to display."));
         }
 
@@ -67,6 +98,17 @@ impl WebRulox {
         JsValue::from_serde(&self.parse_tree).unwrap()
     }
 
+    // Has `interpret` hand each printed line to `callback` as soon as it's printed,
+    // leaving only the diagnostics in the array it returns
+    pub fn set_output(&mut self, callback: js_sys::Function) {
+        self.output = Some(callback);
+    }
+
+    // Makes `source` importable by the program as `import "path";`
+    pub fn add_module(&mut self, path: String, source: String) {
+        self.modules.add(&path, source);
+    }
+
     pub fn interpret(&mut self) -> JsValue {
         let output = self.run();
 
@@ -77,6 +119,10 @@ impl WebRulox {
         self.had_errors
     }
 
+    pub fn diagnostics(&self) -> JsValue {
+        JsValue::from_serde(&self.diagnostics).unwrap()
+    }
+
     pub fn get_environment(&self) -> String {
         self.environments.clone()
     }
@@ -84,39 +130,21 @@ impl WebRulox {
     fn tokenize(&mut self) -> Vec<Token> {
         let mut temp_rulox = Rulox::new(self.source.clone());
         let scanner = Scanner::new(self.source.clone(), &mut temp_rulox);
-        scanner.scan_tokens()
+        let tokens = scanner.scan_tokens();
+        self.collect_diagnostics(&temp_rulox);
+        tokens
     }
 
     fn parse(&mut self) -> Vec<Stmt> {
         let mut temp_rulox = Rulox::new(self.source.clone());
         let mut parser = Parser::new(self.token_stream.clone(), &mut temp_rulox);
-        parser.parse()
-    }
-
-    fn error_line(&mut self, line: usize, msg: String) {
-        self.report((line, 0), "", msg);
-    }
-
-    fn error_token(
-        &mut self,
-        token_type: String,
-        token_line: usize,
-        token_col: usize,
-        msg: String,
-    ) {
-        self.report(
-            (token_line, token_col),
-            &format!("at '{}'", token_type),
-            msg,
-        );
+        let statements = parser.parse();
+        self.collect_diagnostics(&temp_rulox);
+        statements
     }
 
-    fn report(&mut self, pos: (usize, usize), ubi: &str, msg: String) {
-        let (line, col) = pos;
-        self.error_msg.push(format!(
-            "[Line {} Col {}] Error {}: {}",
-            line, col, ubi, msg
-        ));
-        self.had_errors = true;
+    fn collect_diagnostics(&mut self, rulox: &Rulox) {
+        self.diagnostics.extend_from_slice(rulox.diagnostics());
+        self.had_errors |= rulox.had_errors;
     }
 }
@@ -33,34 +33,36 @@ const processErrorLines = ((errorIndexes) => {
 });
 
 // Retrieve error information => array of array[line of error, column of error]
-const getErrorIndexes = ((errorMsgs) => {
-    let indexes = [];
-    errorMsgs.forEach((elem) => {
-        const matchLine = elem.match(/\bLine (\d+)/);
-        const matchCol = elem.match(/\bCol (\d+)/);
-        if (matchLine !== null && matchCol !== null) {
-            const errLine = Number(matchLine[1] - 1);
-            const errCol = Number(matchCol[1]);
-            indexes.push([errLine, errCol]);
-        }
-    });
-    return indexes;
+const getErrorIndexes = ((diagnostics) => {
+    return diagnostics
+        .filter((diagnostic) => diagnostic.severity === "Error" && diagnostic.span !== null)
+        .map((diagnostic) => [diagnostic.span.start.line - 1, diagnostic.span.start.col]);
 });
 
 compileBtn.addEventListener('click', () => {
     const rulox = callCompiler();
     const tokens = rulox.tokens();
     const parseTree = rulox.parse_tree();
-    const output = rulox.interpret();
+
+    // Printed lines are appended as the program runs, followed by any diagnostics
+    const printed = document.createElement("pre");
+    resultArea.replaceChildren(printed);
+    rulox.set_output((line) => printed.append(line + "\n"));
+    const diagnostics = rulox.interpret();
+
     const env = rulox.get_environment();
     if (rulox.had_errors()) {
-        resultArea.innerHTML = "<pre>Compilation error.\n" + output.join("\n") + "</pre>";
-        const errIndexes = getErrorIndexes(output);
+        printed.prepend("Compilation error.\n");
+        printed.append(diagnostics.join("\n"));
+        const errIndexes = getErrorIndexes(rulox.diagnostics());
         processErrorLines(errIndexes);
     } else {
         tokenArea.innerHTML = "<pre>" + JSON.stringify(tokens, null, 2) + "</pre>";
         astArea.innerHTML = "<pre>" + JSON.stringify(parseTree, null, 2) + "</pre>";
-        resultArea.innerHTML = "<pre>" + output.join("\n") + "</pre>";
+        printed.append(diagnostics.join("\n"));
+        if (printed.textContent === "") {
+            printed.textContent = "No output to display.";
+        }
         envArea.innerHTML = "<pre>" + env + "</pre>";
     }
 });